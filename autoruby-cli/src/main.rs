@@ -9,7 +9,14 @@ use std::{
 };
 
 use autoruby::{
-    format::{self, Format, WithKatakana},
+    annotate::{Annotator, Penalty, RenderMode, TokenizerMode},
+    dictionary::{
+        self, build_validated, export, BuildError, CompactDictionary, Dictionary, DictionaryFormat,
+        LayeredDictionary, Lookup, MalformedLine, Statistics, TokenizerFormat, UnparsedLine,
+        ValidatedBuild, Validation,
+    },
+    document::{self, Document},
+    format::{self, Format, Parse, WithKatakana},
     select::{self, Select},
 };
 use clap::{Args, Parser, Subcommand, ValueEnum};
@@ -26,9 +33,23 @@ struct Arguments {
 enum Command {
     /// Annotate text
     Annotate(AnnotateArgs),
+    /// Remove existing annotations from text
+    Strip(StripArgs),
+    /// Manage dictionaries
+    #[command(subcommand)]
+    Dict(DictCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum DictCommand {
+    /// Compile furigana source files into a dictionary
+    Build(DictBuildArgs),
+    /// Write the entries of a dictionary in a text format
+    Export(DictExportArgs),
 }
 
 #[derive(Args, Debug)]
+#[allow(clippy::struct_excessive_bools)]
 struct AnnotateArgs {
     /// File to read input from, otherwise STDIN
     input_path: Option<PathBuf>,
@@ -36,6 +57,17 @@ struct AnnotateArgs {
     /// File to write output to, otherwise STDOUT
     output_path: Option<PathBuf>,
 
+    /// Dictionary to use instead of the integrated one, either a
+    /// `JmdictFurigana.txt` file, a binary `.bin` file or a compact `.ardc`
+    /// file.
+    #[arg(long, value_name = "PATH")]
+    dictionary: Option<PathBuf>,
+
+    /// User dictionary whose entries take priority over the dictionary. May
+    /// be given more than once; later ones take priority over earlier ones.
+    #[arg(long = "user-dictionary", value_name = "PATH")]
+    user_dictionaries: Vec<PathBuf>,
+
     /// Include common kanji readings.
     #[arg(short = 'c', long)]
     include_common: bool,
@@ -51,6 +83,95 @@ struct AnnotateArgs {
     /// Only annotate the first occurrence of a word.
     #[arg(long, short = '1')]
     only_first: bool,
+
+    /// How the readings of a word are annotated.
+    #[arg(value_enum, long, default_value_t = RubyMode::Mono)]
+    ruby: RubyMode,
+
+    /// Split long compounds into shorter words when tokenizing.
+    #[arg(long)]
+    decompose: bool,
+
+    /// Keep existing annotations in the output format as they are.
+    #[arg(long)]
+    preserve: bool,
+
+    /// Look up full-width ASCII, half-width katakana and old forms of kanji
+    /// by their standard forms.
+    #[arg(long)]
+    normalize_variants: bool,
+
+    /// Treat the input as plain text instead of a document in the output format.
+    #[arg(long)]
+    plain: bool,
+
+    /// Additional HTML element whose content should not be annotated.
+    #[arg(long = "skip-element", value_name = "ELEMENT")]
+    skip_elements: Vec<String>,
+
+    /// Additional LaTeX command whose arguments should not be annotated.
+    #[arg(long = "skip-command", value_name = "COMMAND")]
+    skip_commands: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct StripArgs {
+    /// File to read input from, otherwise STDIN
+    input_path: Option<PathBuf>,
+
+    /// File to write output to, otherwise STDOUT
+    output_path: Option<PathBuf>,
+
+    /// Format of the annotations to remove
+    #[arg(value_enum, long, short = 'f')]
+    format: OutputFormat,
+
+    /// Treat the input as plain text instead of a document in the format.
+    #[arg(long)]
+    plain: bool,
+}
+
+#[derive(Args, Debug)]
+struct DictBuildArgs {
+    /// Furigana source files in the `JmdictFurigana.txt` format. Entries of
+    /// later files replace those of earlier ones.
+    #[arg(required = true)]
+    sources: Vec<PathBuf>,
+
+    /// File to write the dictionary to, in the compact format if it ends in
+    /// `.ardc`, otherwise in the binary format.
+    #[arg(long, short = 'o', value_name = "PATH")]
+    output: PathBuf,
+
+    /// `JMdict` XML file to take the frequency of entries from, instead of
+    /// the embedded one.
+    #[arg(long, value_name = "PATH")]
+    jmdict: Option<PathBuf>,
+
+    /// `JMnedict` XML file to add proper names from.
+    #[arg(long, value_name = "PATH")]
+    jmnedict: Option<PathBuf>,
+
+    /// Fail on the first line that cannot be parsed or has malformed reading
+    /// spans, instead of reporting all of them.
+    #[arg(long)]
+    strict: bool,
+}
+
+#[derive(Args, Debug)]
+struct DictExportArgs {
+    /// File to write output to, otherwise STDOUT
+    output_path: Option<PathBuf>,
+
+    /// Dictionary to export instead of the integrated one, either a
+    /// `JmdictFurigana.txt` file, a binary `.bin` file or a compact `.ardc`
+    /// file.
+    #[arg(long, value_name = "PATH")]
+    dictionary: Option<PathBuf>,
+
+    /// Output format
+    #[arg(value_enum, long, short = 'f', default_value_t = ExportFormat::Jsonl)]
+    format: ExportFormat,
 }
 
 fn input(input_path: Option<impl AsRef<Path>>) -> String {
@@ -73,6 +194,199 @@ fn output(output_path: Option<impl AsRef<Path>>) -> Box<dyn Write> {
     )
 }
 
+fn load_dictionary(path: &Path) -> Box<dyn Lookup> {
+    // Compact dictionaries are queried in place rather than decoded.
+    let loaded = if DictionaryFormat::from_path(path) == DictionaryFormat::Compact {
+        CompactDictionary::open(path).map(|d| Box::new(d) as Box<dyn Lookup>)
+    } else {
+        Dictionary::load_from_path(path).map(|d| Box::new(d) as Box<dyn Lookup>)
+    };
+    loaded.unwrap_or_else(|e| {
+        eprintln!("Could not load dictionary {}: {e}", path.display());
+        std::process::exit(1);
+    })
+}
+
+fn add_user_dictionary(dictionary: &mut LayeredDictionary, path: &Path) {
+    let added = fs::File::open(path)
+        .map_err(Into::into)
+        .and_then(|file| dictionary.add_layer(std::io::BufReader::new(file)));
+    if let Err(e) = added {
+        eprintln!("Could not load user dictionary {}: {e}", path.display());
+        std::process::exit(1);
+    }
+}
+
+/// Writes the entries of the user dictionaries to a temporary lindera user
+/// dictionary for the embedded `UniDic`, so that the tokenizer keeps their
+/// words together. The file is created with a unique name, and deleted when
+/// dropped.
+fn write_tokenizer_dictionary(dictionary: &LayeredDictionary) -> tempfile::NamedTempFile {
+    let written = tempfile::Builder::new()
+        .prefix("autoruby-")
+        .suffix(".csv")
+        .tempfile()
+        .and_then(|mut file| {
+            let mut writer = std::io::BufWriter::new(&mut file);
+            dictionary.write_tokenizer_dictionary(&mut writer, TokenizerFormat::UniDic)?;
+            writer.flush()?;
+            drop(writer);
+            Ok(file)
+        });
+    written.unwrap_or_else(|e| {
+        eprintln!("Could not write tokenizer dictionary: {e}");
+        std::process::exit(1);
+    })
+}
+
+fn read_xml(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {e}", path.display());
+        std::process::exit(1);
+    })
+}
+
+fn build_dictionary(args: &DictBuildArgs) {
+    let validation = if args.strict {
+        Validation::Strict
+    } else {
+        Validation::SkipUnparsed
+    };
+
+    let mut dictionary: Option<Dictionary> = None;
+    let mut unparsed_count = 0;
+    for path in &args.sources {
+        let built = fs::File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| build_validated(std::io::BufReader::new(file), validation));
+        let ValidatedBuild {
+            dictionary: built,
+            malformed,
+            unparsed,
+        } = built.unwrap_or_else(|e| {
+            match e {
+                BuildError::Parse { line, content } => {
+                    eprintln!("{}:{line}: could not parse line: {content}", path.display());
+                }
+                BuildError::Malformed { line, issue } => {
+                    eprintln!(
+                        "{}:{line}: malformed reading spans: {issue}",
+                        path.display()
+                    );
+                }
+                BuildError::Io(e) => eprintln!("Could not build {}: {e}", path.display()),
+            }
+            std::process::exit(1);
+        });
+
+        for MalformedLine { line, issue } in &malformed {
+            eprintln!(
+                "{}:{line}: repaired malformed reading spans: {issue}",
+                path.display()
+            );
+        }
+        for UnparsedLine { line, content } in &unparsed {
+            eprintln!("{}:{line}: could not parse line: {content}", path.display());
+        }
+        println!(
+            "{}: {} entries, {} lines repaired, {} lines unparsed",
+            path.display(),
+            built.len(),
+            malformed.len(),
+            unparsed.len()
+        );
+        unparsed_count += unparsed.len();
+
+        match &mut dictionary {
+            Some(dictionary) => dictionary.merge(built),
+            None => dictionary = Some(built),
+        }
+    }
+    if unparsed_count > 0 {
+        eprintln!(
+            "Could not build {}: {unparsed_count} lines could not be parsed",
+            args.output.display()
+        );
+        std::process::exit(1);
+    }
+    let Some(mut dictionary) = dictionary else {
+        return;
+    };
+
+    if let Some(path) = &args.jmdict {
+        dictionary.add_frequencies(&read_xml(path));
+    }
+    if let Some(path) = &args.jmnedict {
+        dictionary.add_names(&read_xml(path));
+    }
+
+    let written = fs::File::create(&args.output)
+        .map_err(Into::into)
+        .and_then(|file| {
+            let writer = std::io::BufWriter::new(file);
+            if DictionaryFormat::from_path(&args.output) == DictionaryFormat::Compact {
+                Ok(dictionary.write_compact(writer)?)
+            } else {
+                dictionary.write_binary(writer)
+            }
+        });
+    if let Err(e) = written {
+        eprintln!("Could not write {}: {e}", args.output.display());
+        std::process::exit(1);
+    }
+
+    let Statistics {
+        entries,
+        words,
+        common,
+        names,
+        kanji,
+    } = dictionary.statistics();
+    println!("{}:", args.output.display());
+    println!("  {entries} entries for {words} words");
+    println!("  {common} common entries");
+    println!("  {names} proper names");
+    println!("  {kanji} kanji with known readings");
+}
+
+fn export_dictionary(args: DictExportArgs) {
+    let writer = std::io::BufWriter::new(output(args.output_path));
+    let format = args.format.into();
+
+    let exported = match &args.dictionary {
+        Some(path) if DictionaryFormat::from_path(path) == DictionaryFormat::Compact => {
+            // Check every record, so that none is left out of the export.
+            CompactDictionary::open(path)
+                .and_then(|d| d.validate().map(|()| d))
+                .map(|d| export(d.entries(), writer, format))
+        }
+        Some(path) => Dictionary::load_from_path(path).map(|d| export(d.entries(), writer, format)),
+        None => Ok(export(integrated_dictionary().entries(), writer, format)),
+    };
+    let exported = exported.unwrap_or_else(|e| {
+        eprintln!(
+            "Could not load dictionary {}: {e}",
+            args.dictionary.unwrap_or_default().display()
+        );
+        std::process::exit(1);
+    });
+    if let Err(e) = exported {
+        eprintln!("Could not export dictionary: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "integrated")]
+fn integrated_dictionary() -> &'static CompactDictionary {
+    autoruby::integrated_dictionary()
+}
+
+#[cfg(not(feature = "integrated"))]
+fn integrated_dictionary() -> &'static CompactDictionary {
+    eprintln!("No integrated dictionary: specify one with --dictionary.");
+    std::process::exit(1);
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 enum OutputFormat {
     #[value(alias = "md")]
@@ -80,6 +394,49 @@ enum OutputFormat {
     Html,
     #[value(alias = "tex")]
     Latex,
+    Aozora,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum RubyMode {
+    /// One annotation per reading
+    Mono,
+    /// One annotation for the whole word
+    Group,
+    /// One annotation for the whole word, keeping per-kanji readings
+    Jukugo,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum ExportFormat {
+    /// One JSON object per line
+    #[value(alias = "json")]
+    Jsonl,
+    /// CSV with a header row
+    Csv,
+    /// The `text|reading|spans` format of `JmdictFurigana.txt`
+    #[value(alias = "txt")]
+    Source,
+}
+
+impl From<ExportFormat> for dictionary::ExportFormat {
+    fn from(value: ExportFormat) -> Self {
+        match value {
+            ExportFormat::Jsonl => dictionary::ExportFormat::JsonLines,
+            ExportFormat::Csv => dictionary::ExportFormat::Csv,
+            ExportFormat::Source => dictionary::ExportFormat::Source,
+        }
+    }
+}
+
+impl From<RubyMode> for RenderMode {
+    fn from(value: RubyMode) -> Self {
+        match value {
+            RubyMode::Mono => RenderMode::Mono,
+            RubyMode::Group => RenderMode::Group,
+            RubyMode::Jukugo => RenderMode::Jukugo,
+        }
+    }
 }
 
 impl OutputFormat {
@@ -88,6 +445,52 @@ impl OutputFormat {
             OutputFormat::Markdown => Box::new(format::Markdown),
             OutputFormat::Html => Box::new(format::Html),
             OutputFormat::Latex => Box::new(format::Latex),
+            OutputFormat::Aozora => Box::new(format::Aozora),
+        }
+    }
+
+    pub fn parser(self) -> Box<dyn Parse + Sync> {
+        match self {
+            OutputFormat::Markdown => Box::new(format::Markdown),
+            OutputFormat::Html => Box::new(format::Html),
+            OutputFormat::Latex => Box::new(format::Latex),
+            OutputFormat::Aozora => Box::new(format::Aozora),
+        }
+    }
+
+    pub fn document(self, skip_elements: &[String], skip_commands: &[String]) -> Box<dyn Document> {
+        match self {
+            OutputFormat::Markdown => Box::new(document::Markdown),
+            OutputFormat::Html => {
+                let mut html = document::Html::default();
+                html.skip_elements.extend_from_slice(skip_elements);
+                Box::new(html)
+            }
+            OutputFormat::Latex => {
+                let mut latex = document::Latex::default();
+                latex.skip_commands.extend_from_slice(skip_commands);
+                Box::new(latex)
+            }
+            OutputFormat::Aozora => Box::new(document::Plain),
+        }
+    }
+
+    /// The document to remove annotations from. Unlike for annotating, the
+    /// markup of the annotations themselves is not skipped.
+    pub fn strip_document(self) -> Box<dyn Document> {
+        match self {
+            OutputFormat::Html => {
+                let mut html = document::Html::default();
+                html.skip_elements
+                    .retain(|e| !matches!(e.as_str(), "ruby" | "rb" | "rt" | "rp" | "rtc"));
+                Box::new(html)
+            }
+            OutputFormat::Latex => {
+                let mut latex = document::Latex::default();
+                latex.skip_commands.retain(|c| c != "ruby");
+                Box::new(latex)
+            }
+            _ => self.document(&[], &[]),
         }
     }
 }
@@ -100,9 +503,50 @@ async fn main() {
         Command::Annotate(a) => {
             let input_text = input(a.input_path);
 
-            let annotator = autoruby::annotate::Annotator::new_with_integrated_dictionary();
+            let parser = a.format.parser();
+            let dictionary = a.dictionary.as_deref().map(load_dictionary);
+            let mut dictionary = LayeredDictionary::new(
+                dictionary
+                    .as_deref()
+                    .unwrap_or_else(|| integrated_dictionary() as &dyn Lookup),
+            );
+            for path in &a.user_dictionaries {
+                add_user_dictionary(&mut dictionary, path);
+            }
+            let mut builder = Annotator::builder(&dictionary);
+            if a.preserve {
+                builder = builder.preserve_existing(&*parser);
+            }
+            if a.normalize_variants {
+                builder = builder.normalize_variants(true);
+            }
+            if a.decompose {
+                builder = builder.mode(TokenizerMode::Decompose(Penalty::default()));
+            }
+            let tokenizer_dictionary =
+                (!a.user_dictionaries.is_empty()).then(|| write_tokenizer_dictionary(&dictionary));
+            if let Some(file) = &tokenizer_dictionary {
+                builder = builder.user_dictionary(file.path());
+            }
+            let annotator = builder.build();
+            drop(tokenizer_dictionary);
+            let annotator = annotator.unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+
+            let document = if a.plain {
+                Box::new(document::Plain)
+            } else {
+                a.format.document(&a.skip_elements, &a.skip_commands)
+            };
 
-            let annotated = annotator.annotate(&input_text);
+            let annotated = annotator
+                .try_annotate_document(&input_text, &*document)
+                .unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                });
 
             let formatter = a.format.formatter();
             let formatter = {
@@ -114,22 +558,43 @@ async fn main() {
             };
 
             let selector = match (a.only_first, a.include_common) {
-                (true, true) => {
-                    Box::new(select::filter::FirstOccurrence::new(select::heuristic::All))
-                        as Box<dyn Select>
-                }
-                (true, false) => Box::new(select::filter::FirstOccurrence::new(
-                    select::heuristic::UncommonOnly,
-                )) as Box<dyn Select>,
+                (true, true) => Box::new(
+                    select::filter::FirstOccurrence::new(select::heuristic::All).counting_locked(),
+                ) as Box<dyn Select>,
+                (true, false) => Box::new(
+                    select::filter::FirstOccurrence::new(select::heuristic::UncommonOnly)
+                        .counting_locked(),
+                ) as Box<dyn Select>,
                 (false, true) => Box::new(select::heuristic::All) as Box<dyn Select>,
                 (false, false) => Box::new(select::heuristic::UncommonOnly) as Box<dyn Select>,
             };
 
-            let generated = annotated.render(&*selector, &*formatter);
+            let generated = annotated
+                .try_render_with_mode(&*selector, &*formatter, a.ruby.into())
+                .unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                });
 
             output(a.output_path)
                 .write_all(generated.as_bytes())
                 .expect("Could not write output.");
         }
+        Command::Strip(s) => {
+            let input_text = input(s.input_path);
+
+            let document = if s.plain {
+                Box::new(document::Plain)
+            } else {
+                s.format.strip_document()
+            };
+            let stripped = s.format.parser().strip_document(&input_text, &*document);
+
+            output(s.output_path)
+                .write_all(stripped.as_bytes())
+                .expect("Could not write output.");
+        }
+        Command::Dict(DictCommand::Build(d)) => build_dictionary(&d),
+        Command::Dict(DictCommand::Export(d)) => export_dictionary(d),
     }
 }
@@ -0,0 +1,62 @@
+//! Compares loading and querying the integrated dictionary in the compact
+//! format with the bincode format.
+
+use std::hint::black_box;
+
+use autoruby::dictionary::{CompactDictionary, Dictionary, DictionaryFormat, Lookup};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const COMPACT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/dict.ardc"));
+
+const WORDS: [&str; 8] = [
+    "日本",
+    "勉強",
+    "東京",
+    "漢字",
+    "明日",
+    "計算",
+    "振り仮名",
+    "辞書",
+];
+
+fn lookup(dictionary: &dyn Lookup) -> usize {
+    WORDS
+        .iter()
+        .map(|word| {
+            dictionary.lookup_word(black_box(word)).count()
+                + dictionary.lookup_prefixed(black_box(word)).count()
+        })
+        .sum()
+}
+
+fn dictionary(c: &mut Criterion) {
+    let dictionary = Dictionary::from_reader(COMPACT, DictionaryFormat::Compact).unwrap();
+    let mut bincode = vec![];
+    dictionary.write_binary(&mut bincode).unwrap();
+
+    c.bench_function("load/bincode", |b| {
+        b.iter(|| Dictionary::from_reader(black_box(&bincode[..]), DictionaryFormat::Binary))
+    });
+    c.bench_function("load/compact", |b| {
+        b.iter(|| CompactDictionary::from_static(black_box(COMPACT)))
+    });
+
+    let compact = CompactDictionary::from_static(COMPACT).unwrap();
+    c.bench_function("lookup/bincode", |b| b.iter(|| lookup(&dictionary)));
+    c.bench_function("lookup/compact", |b| b.iter(|| lookup(&compact)));
+
+    // A single invocation of the CLI: load, then look up a few words.
+    c.bench_function("load_and_lookup/bincode", |b| {
+        b.iter(|| {
+            let dictionary =
+                Dictionary::from_reader(black_box(&bincode[..]), DictionaryFormat::Binary).unwrap();
+            lookup(&dictionary)
+        })
+    });
+    c.bench_function("load_and_lookup/compact", |b| {
+        b.iter(|| lookup(&CompactDictionary::from_static(black_box(COMPACT)).unwrap()))
+    });
+}
+
+criterion_group!(benches, dictionary);
+criterion_main!(benches);
@@ -1,5 +1,17 @@
 #![allow(unused)]
 
+#[cfg(feature = "integrated")]
+#[path = "./src/align.rs"]
+mod align;
+
+#[cfg(feature = "integrated")]
+#[path = "./src/chars.rs"]
+mod chars;
+
+#[cfg(feature = "integrated")]
+#[path = "./src/compact.rs"]
+mod compact;
+
 #[cfg(feature = "integrated")]
 #[path = "./src/dictionary.rs"]
 mod dictionary;
@@ -8,11 +20,150 @@ mod dictionary;
 #[path = "./src/parse.rs"]
 mod parse;
 
+/// How to provide the furigana dictionary without downloading it.
+#[cfg(feature = "integrated")]
+fn furigana_help() -> String {
+    format!(
+        "\
+To build the integrated dictionary offline, download the furigana dictionary from
+    {}
+and either set AUTORUBY_FURIGANA_PATH to its path, or save it as `furigana.txt`
+in the directory given by AUTORUBY_CACHE_DIR. Set AUTORUBY_FURIGANA_SHA256 to
+verify its checksum. Alternatively, disable the `integrated` feature and load a
+dictionary at runtime.",
+        dictionary::DOWNLOAD_URL
+    )
+}
+
+/// How to provide the proper name dictionary.
+#[cfg(feature = "names")]
+const JMNEDICT_HELP: &str = "\
+The `names` feature requires the JMnedict proper name dictionary. Download and extract
+`JMnedict.xml` from https://www.edrdg.org/enamdict/enamdict_doc.html and either set
+AUTORUBY_JMNEDICT_PATH to its path, or save it in the directory given by
+AUTORUBY_CACHE_DIR. Alternatively, disable the `names` feature and add names at runtime
+with `Dictionary::add_names`.";
+
+/// How to keep the `jmdict` dependency from downloading its data.
+#[cfg(feature = "integrated")]
+const JMDICT_HELP: &str = "\
+The `jmdict` dependency downloads its data while building unless RUST_JMDICT_ENTRYPACK
+is set to the path of a local copy of its entry pack (`entrypack-v1-2021-07-19.json.gz`
+from https://dl.xyrillian.de/jmdict/). Set it in the environment: the build of `jmdict`
+does not read `.env`.";
+
+/// Reports why the dictionary could not be built, and how to fix it if
+/// `help` is not empty, and exits without panicking.
+#[cfg(feature = "integrated")]
+fn fail(message: &str, help: &str) -> ! {
+    eprintln!("error: {message}");
+    if !help.is_empty() {
+        eprintln!();
+        eprintln!("{help}");
+    }
+    std::process::exit(1);
+}
+
+/// Whether an environment variable is set to a truthy value.
+#[cfg(feature = "integrated")]
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| !matches!(v.as_str(), "" | "0" | "false"))
+}
+
+/// Reads the furigana dictionary source, downloading it to `cache_path` if
+/// it is missing and downloads are allowed.
+#[cfg(all(feature = "integrated", not(feature = "dummy")))]
+async fn furigana_source(cache_path: &std::path::Path) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+
+    println!("cargo:rerun-if-env-changed=AUTORUBY_FURIGANA_PATH");
+    println!("cargo:rerun-if-env-changed=AUTORUBY_FURIGANA_SHA256");
+    println!("cargo:rerun-if-env-changed=AUTORUBY_OFFLINE");
+
+    let source_path = std::env::var_os("AUTORUBY_FURIGANA_PATH");
+    let path = source_path
+        .as_ref()
+        .map_or(cache_path, std::path::Path::new);
+
+    if !path.exists() {
+        if source_path.is_some() {
+            fail(
+                &format!(
+                    "AUTORUBY_FURIGANA_PATH is set, but {} does not exist.",
+                    path.display()
+                ),
+                &furigana_help(),
+            );
+        }
+        if env_flag("AUTORUBY_OFFLINE") {
+            fail(
+                &format!(
+                    "{} does not exist, and AUTORUBY_OFFLINE forbids downloading it.",
+                    path.display()
+                ),
+                &furigana_help(),
+            );
+        }
+
+        println!(
+            "cargo:warning=Downloading the furigana dictionary from {}",
+            dictionary::DOWNLOAD_URL
+        );
+        let response = match reqwest::get(dictionary::DOWNLOAD_URL)
+            .await
+            .and_then(reqwest::Response::error_for_status)
+        {
+            Ok(response) => response,
+            Err(e) => fail(
+                &format!("Could not download the furigana dictionary: {e}"),
+                &furigana_help(),
+            ),
+        };
+        let bytes = match response.bytes().await {
+            Ok(bytes) => bytes,
+            Err(e) => fail(
+                &format!("Could not download the furigana dictionary: {e}"),
+                &furigana_help(),
+            ),
+        };
+        if let Err(e) = std::fs::write(path, &bytes) {
+            fail(&format!("Could not write {}: {e}", path.display()), "");
+        }
+    }
+
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let bytes = std::fs::read(path).unwrap_or_else(|e| {
+        fail(
+            &format!("Could not read {}: {e}", path.display()),
+            &furigana_help(),
+        )
+    });
+
+    let checksum = format!("{:x}", Sha256::digest(&bytes));
+    match std::env::var("AUTORUBY_FURIGANA_SHA256") {
+        Ok(expected) if !expected.trim().eq_ignore_ascii_case(&checksum) => fail(&format!(
+            "The checksum of {} is {checksum}, but AUTORUBY_FURIGANA_SHA256 is {}.",
+            path.display(),
+            expected.trim()
+        ), &furigana_help()),
+        Ok(_) => {}
+        Err(_) => println!(
+            "cargo:warning=Using unverified furigana dictionary {} (sha256 {checksum}). Set AUTORUBY_FURIGANA_SHA256 to verify it.",
+            path.display()
+        ),
+    }
+
+    bytes
+}
+
 #[cfg(feature = "integrated")]
 #[tokio::main]
 async fn main() {
-    use std::{io::BufRead, path::PathBuf};
+    use std::path::PathBuf;
 
+    // Read before `.env`, which the build of `jmdict` does not see.
+    let entrypack = std::env::var_os("RUST_JMDICT_ENTRYPACK");
     dotenvy::dotenv().ok();
 
     let out_dir = std::env::var("OUT_DIR").unwrap();
@@ -20,36 +171,78 @@ async fn main() {
     let cache_dir = std::env::var("AUTORUBY_CACHE_DIR").unwrap_or_else(|_| out_dir.clone());
 
     let furigana_path: PathBuf = [&cache_dir, "./furigana.txt"].iter().collect();
-    let bin_path: PathBuf = [&out_dir, "./dict.bin"].iter().collect();
+    let dict_path: PathBuf = [&out_dir, "./dict.ardc"].iter().collect();
 
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=.env");
+    println!("cargo:rerun-if-env-changed=AUTORUBY_CACHE_DIR");
+    println!("cargo:rerun-if-env-changed=RUST_JMDICT_ENTRYPACK");
 
-    let dictionary_reader = {
+    #[cfg(not(feature = "dummy"))]
+    if env_flag("AUTORUBY_OFFLINE") && !entrypack.is_some_and(|path| path != "default") {
+        fail(
+            "AUTORUBY_OFFLINE is set, but RUST_JMDICT_ENTRYPACK is not set to a local file.",
+            JMDICT_HELP,
+        );
+    }
+
+    let source = {
         #[cfg(feature = "dummy")]
         {
             let dummy_dictionary = "有る|ある|0:あ\n";
-            Box::new(dummy_dictionary.as_bytes()) as Box<dyn BufRead>
+            dummy_dictionary.as_bytes().to_vec()
         }
 
         #[cfg(not(feature = "dummy"))]
-        if let Ok(file) = std::fs::File::open(&furigana_path) {
-            Box::new(std::io::BufReader::new(file)) as Box<dyn BufRead>
-        } else {
-            let dictionary_file = reqwest::get(dictionary::DOWNLOAD_URL)
-                .await
-                .unwrap()
-                .text()
-                .await
-                .unwrap();
-            std::fs::write(&furigana_path, dictionary_file).unwrap();
-            let file = std::fs::File::open(&furigana_path).unwrap();
-            Box::new(std::io::BufReader::new(file)) as Box<dyn BufRead>
-        }
+        furigana_source(&furigana_path).await
     };
 
-    let dict = dictionary::build(dictionary_reader).unwrap();
-    std::fs::write(bin_path, bincode::serialize(&dict).unwrap()).unwrap();
+    #[allow(unused_mut)]
+    let dictionary::ValidatedBuild {
+        dictionary: mut dict,
+        malformed,
+        ..
+    } = dictionary::build_validated(&source[..], dictionary::Validation::Lenient).unwrap_or_else(
+        |e| {
+            fail(
+                &format!("Invalid furigana dictionary: {e}"),
+                &furigana_help(),
+            )
+        },
+    );
+    if let Some(first) = malformed.first() {
+        println!(
+            "cargo:warning=Repaired {} malformed reading spans in the furigana dictionary, e.g. on line {}: {}",
+            malformed.len(),
+            first.line,
+            first.issue,
+        );
+    }
+
+    #[cfg(all(feature = "names", not(feature = "dummy")))]
+    {
+        println!("cargo:rerun-if-env-changed=AUTORUBY_JMNEDICT_PATH");
+
+        let names_path: PathBuf = std::env::var("AUTORUBY_JMNEDICT_PATH").map_or_else(
+            |_| [&cache_dir, "./JMnedict.xml"].iter().collect(),
+            PathBuf::from,
+        );
+        println!("cargo:rerun-if-changed={}", names_path.display());
+
+        let names = std::fs::read_to_string(&names_path).unwrap_or_else(|e| {
+            fail(
+                &format!("Could not read JMnedict from {}: {e}", names_path.display()),
+                JMNEDICT_HELP,
+            )
+        });
+        dict.add_names(&names);
+    }
+
+    let written = std::fs::File::create(&dict_path)
+        .and_then(|file| dict.write_compact(std::io::BufWriter::new(file)));
+    if let Err(e) = written {
+        fail(&format!("Could not write {}: {e}", dict_path.display()), "");
+    }
 }
 
 #[cfg(not(feature = "integrated"))]
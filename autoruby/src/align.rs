@@ -0,0 +1,585 @@
+//! Alignment of readings with the kanji and kana of a text.
+
+use std::{collections::HashMap, ops::Range};
+
+use wana_kana::{ConvertJapanese, Options};
+
+use crate::{
+    chars::is_kanji,
+    dictionary::{ReadingSpan, TextEntry},
+};
+
+/// A run of kanji, or of other characters, as a range of character indices.
+#[derive(Debug, Clone)]
+enum Run {
+    Kanji(Range<usize>),
+    Kana(Range<usize>),
+}
+
+/// Splits the text into runs of kanji and runs of other characters.
+fn runs(text: &[char]) -> Vec<Run> {
+    let mut runs: Vec<Run> = vec![];
+    for (i, &c) in text.iter().enumerate() {
+        match (runs.last_mut(), is_kanji(c)) {
+            (Some(Run::Kanji(range)), true) | (Some(Run::Kana(range)), false) => range.end = i + 1,
+            (_, true) => runs.push(Run::Kanji(i..i + 1)),
+            (_, false) => runs.push(Run::Kana(i..i + 1)),
+        }
+    }
+    runs
+}
+
+/// Returns the ranges of the runs of kanji.
+fn kanji_runs(runs: &[Run]) -> Vec<Range<usize>> {
+    runs.iter()
+        .filter_map(|run| match run {
+            Run::Kanji(range) => Some(range.clone()),
+            Run::Kana(_) => None,
+        })
+        .collect()
+}
+
+/// Converts katakana to hiragana, leaving all other characters unchanged.
+/// Characters are converted one at a time, so that `ー` is kept as is rather
+/// than spelled out from the preceding kana.
+fn hiragana(c: char) -> char {
+    let options = Options {
+        pass_romaji: true,
+        ..Options::default()
+    };
+    c.encode_utf8(&mut [0; 4])
+        .to_hiragana_with_opt(options)
+        .chars()
+        .next()
+        .unwrap_or(c)
+}
+
+/// Kana ending in each vowel.
+const A_ROW: &str = "あかがさざただなはばぱまやらわぁゃゎ";
+const I_ROW: &str = "いきぎしじちぢにひびぴみりぃ";
+const U_ROW: &str = "うくぐすずつづぬふぶぷむゆるぅゅ";
+const E_ROW: &str = "えけげせぜてでねへべぺめれぇ";
+const O_ROW: &str = "おこごそぞとどのほぼぽもよろをぉょ";
+
+/// Whether the kana lengthens the vowel of the preceding kana, e.g. the `う`
+/// in `こう` or the `い` in `せい`.
+fn extends(preceding: Option<char>, c: char) -> bool {
+    c == 'ー'
+        || preceding.is_some_and(|p| {
+            (O_ROW.contains(p) && matches!(c, 'う' | 'お'))
+                || (E_ROW.contains(p) && matches!(c, 'い' | 'え'))
+        })
+}
+
+/// Whether two kana are equivalent, given the kana preceding them. Long
+/// vowels match loosely, since the tokenizer gives readings as pronounced
+/// (e.g. `せんせー` for `せんせい`).
+fn kana_eq(preceding: Option<char>, a: char, b: char) -> bool {
+    let (a, b) = (hiragana(a), hiragana(b));
+    a == b || (extends(preceding, a) && extends(preceding, b))
+}
+
+/// Spells out the long vowels of a reading as pronounced, e.g. `きょう` for
+/// `きょー`. As in most readings of kanji, `ー` stands for `う` after the o
+/// row and for `い` after the e row.
+fn spell_long_vowels(reading: &[char]) -> String {
+    let mut spelled = String::with_capacity(reading.len() * 3);
+    for &c in reading {
+        let vowel = spelled.chars().next_back().and_then(|p| {
+            [A_ROW, I_ROW, U_ROW, E_ROW, O_ROW]
+                .iter()
+                .position(|row| row.contains(p))
+                .and_then(|i| "あいういう".chars().nth(i))
+        });
+        match vowel {
+            Some(vowel) if c == 'ー' => spelled.push(vowel),
+            _ => spelled.push(c),
+        }
+    }
+    spelled
+}
+
+/// Spells the reading of a text from its reading spans and its other
+/// characters, which are taken as kana.
+pub(crate) fn spelled_reading(text: &str, reading_spans: &[ReadingSpan]) -> String {
+    let mut reading = String::with_capacity(text.len());
+    for (i, c) in text.chars().enumerate() {
+        let span = reading_spans
+            .iter()
+            .find(|s| (s.start_index as usize..=s.end_index as usize).contains(&i));
+        match span {
+            Some(span) if span.start_index as usize == i => reading.push_str(&span.text),
+            Some(_) => {}
+            None => reading.push(hiragana(c)),
+        }
+    }
+    reading
+}
+
+/// Whether `text` matches `reading` at the given position.
+fn matches_at(text: &[char], reading: &[char], position: usize) -> bool {
+    reading.len() >= position + text.len()
+        && text.iter().enumerate().all(|(i, &c)| {
+            let preceding = (position + i).checked_sub(1).map(|p| hiragana(reading[p]));
+            kana_eq(preceding, c, reading[position + i])
+        })
+}
+
+/// Whether two readings are equivalent, allowing for long vowels written as
+/// pronounced.
+pub(crate) fn readings_match(a: &str, b: &str) -> bool {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    a.len() == b.len() && matches_at(&a, &b, 0)
+}
+
+/// The score of an alignment, and the range of the reading taken by each run
+/// of kanji.
+type ScoredAlignment = (usize, Vec<Range<usize>>);
+
+/// Finds the best way of splitting a reading across the runs of a text.
+/// Kana must match the reading; each run of kanji takes at least one
+/// character of it.
+///
+/// The best alignments from each run and position in the reading are
+/// memoized, so that texts with many runs of kanji are aligned in
+/// polynomial time.
+struct Aligner<'a, F> {
+    text: &'a [char],
+    runs: &'a [Run],
+    reading: &'a [char],
+    /// Rates the range of the reading taken by a run of kanji, given the
+    /// index of the run among the runs of kanji.
+    score: F,
+    memo: HashMap<(usize, usize), Option<ScoredAlignment>>,
+}
+
+impl<'a, F: FnMut(usize, &Range<usize>) -> usize> Aligner<'a, F> {
+    fn new(text: &'a [char], runs: &'a [Run], reading: &'a [char], score: F) -> Self {
+        Self {
+            text,
+            runs,
+            reading,
+            score,
+            memo: HashMap::new(),
+        }
+    }
+
+    /// Returns the range of the reading taken by each run of kanji in the
+    /// alignment with the highest total score, and the first among those
+    /// (by the end of the range of each run of kanji in turn).
+    fn best(mut self) -> Option<Vec<Range<usize>>> {
+        self.best_from(0, 0, 0).map(|(_, alignment)| alignment)
+    }
+
+    /// Returns the best alignment of the runs from `run` on, which is the
+    /// `kanji`th run of kanji or precedes it, with the reading from
+    /// `position` on, and its score.
+    fn best_from(&mut self, run: usize, kanji: usize, position: usize) -> Option<ScoredAlignment> {
+        if let Some(best) = self.memo.get(&(run, position)) {
+            return best.clone();
+        }
+
+        let best = match self.runs.get(run) {
+            None => (position == self.reading.len()).then(|| (0, vec![])),
+            Some(Run::Kana(range)) => {
+                if matches_at(&self.text[range.clone()], self.reading, position) {
+                    self.best_from(run + 1, kanji, position + range.len())
+                } else {
+                    None
+                }
+            }
+            Some(Run::Kanji(_)) => {
+                let rest_len = self.runs[run + 1..]
+                    .iter()
+                    .map(|run| match run {
+                        Run::Kana(range) => range.len(),
+                        Run::Kanji(_) => 1,
+                    })
+                    .sum::<usize>();
+
+                let mut best: Option<ScoredAlignment> = None;
+                for end in position + 1..=self.reading.len().saturating_sub(rest_len) {
+                    let Some((rest_score, rest)) = self.best_from(run + 1, kanji + 1, end) else {
+                        continue;
+                    };
+                    let score = (self.score)(kanji, &(position..end)) + rest_score;
+                    match &best {
+                        Some((best_score, _)) if *best_score >= score => {}
+                        _ => {
+                            let mut alignment = Vec::with_capacity(rest.len() + 1);
+                            alignment.push(position..end);
+                            alignment.extend(rest);
+                            best = Some((score, alignment));
+                        }
+                    }
+                }
+                best
+            }
+        };
+
+        self.memo.insert((run, position), best.clone());
+        best
+    }
+}
+
+/// Whether `b` is the voiced form of `a`, e.g. `が` for `か` or `ぱ` for `は`.
+fn voices(a: char, b: char) -> bool {
+    const UNVOICED: &str = "かきくけこさしすせそたちつてとはひふへほ";
+    const VOICED: &str = "がぎぐげござじずぜぞだぢづでどばびぶべぼ";
+    const SEMI_VOICED: &str = "ぱぴぷぺぽ";
+
+    UNVOICED.chars().position(|c| c == a).is_some_and(|i| {
+        VOICED.chars().nth(i) == Some(b) || (i >= 15 && SEMI_VOICED.chars().nth(i - 15) == Some(b))
+    })
+}
+
+/// Whether part of a reading matches a known reading of a kanji, allowing for
+/// sound changes in compounds: a voiced first kana (e.g. `がみ` for `かみ`)
+/// and a geminated last kana (e.g. `がっ` for `がく`).
+fn matches_kanji_reading(part: &[char], known: &str) -> bool {
+    let known = known.chars().map(hiragana).collect::<Vec<_>>();
+    part.len() == known.len()
+        && part.iter().zip(&known).enumerate().all(|(i, (&p, &k))| {
+            let preceding = i.checked_sub(1).map(|i| part[i]);
+            kana_eq(preceding, p, k)
+                || (i == 0 && voices(k, p))
+                || (i > 0
+                    && i + 1 == known.len()
+                    && p == 'っ'
+                    && matches!(k, 'つ' | 'く' | 'ち' | 'き'))
+        })
+}
+
+/// Spells part of a reading that matches a known reading of a kanji: as the
+/// known reading, but with the sound changes of the part (see
+/// [`matches_kanji_reading`]), so that long vowels as pronounced are spelled
+/// out.
+fn spell_kanji_reading(part: &[char], known: &str) -> String {
+    part.iter()
+        .zip(known.chars().map(hiragana))
+        .enumerate()
+        .map(|(i, (&p, k))| {
+            if p == k || (i == 0 && voices(k, p)) || p == 'っ' {
+                p
+            } else {
+                k
+            }
+        })
+        .collect()
+}
+
+/// Splits the reading of a run of kanji into known readings of each kanji.
+/// Returns the reading of each kanji.
+fn split_run<'d>(
+    kanji: &[char],
+    reading: &[char],
+    kanji_readings: &impl Fn(char) -> &'d [String],
+) -> Option<Vec<String>> {
+    let Some((&first, rest)) = kanji.split_first() else {
+        return reading.is_empty().then(Vec::new);
+    };
+
+    kanji_readings(first).iter().find_map(|known| {
+        let len = known.chars().count();
+        if len == 0 || len > reading.len() || !matches_kanji_reading(&reading[..len], known) {
+            return None;
+        }
+        let mut readings = split_run(rest, &reading[len..], kanji_readings)?;
+        readings.insert(0, spell_kanji_reading(&reading[..len], known));
+        Some(readings)
+    })
+}
+
+/// Creates a span covering the given range of characters.
+fn span(range: &Range<usize>, text: String) -> Option<ReadingSpan> {
+    Some(ReadingSpan {
+        start_index: u8::try_from(range.start).ok()?,
+        end_index: u8::try_from(range.end.checked_sub(1)?).ok()?,
+        text,
+    })
+}
+
+/// Aligns a dictionary entry with another form of the same word, e.g. an
+/// inflected form of a verb, given the reading of that form.
+///
+/// Runs of kanji that the entry shares with the text keep the entry's spans
+/// if their reading is unchanged; all others are annotated with their part
+/// of the given reading, with long vowels spelled out. The reading of the
+/// result is spelled from its spans and the kana of the text. Returns `None`
+/// if the reading cannot be aligned with the text.
+pub(crate) fn realign(entry: &TextEntry, text: &str, reading: &str) -> Option<TextEntry> {
+    let text_chars = text.chars().collect::<Vec<_>>();
+    let entry_chars = entry.text.chars().collect::<Vec<_>>();
+    let reading_chars = reading.chars().map(hiragana).collect::<Vec<_>>();
+
+    let runs = runs(&text_chars);
+    let kanji_runs = kanji_runs(&runs);
+
+    // The entry's spans for each run of kanji, if they cover it exactly.
+    let hints = kanji_runs
+        .iter()
+        .map(|range| {
+            if entry_chars.get(range.clone()) != Some(&text_chars[range.clone()]) {
+                return None;
+            }
+            let spans = entry
+                .reading_spans
+                .iter()
+                .filter(|s| {
+                    range.contains(&(s.start_index as usize))
+                        && range.contains(&(s.end_index as usize))
+                })
+                .collect::<Vec<_>>();
+            let covers = spans
+                .first()
+                .is_some_and(|s| s.start_index as usize == range.start)
+                && spans
+                    .last()
+                    .is_some_and(|s| s.end_index as usize + 1 == range.end)
+                && spans
+                    .windows(2)
+                    .all(|w| w[1].start_index as usize == w[0].end_index as usize + 1);
+            covers.then(|| {
+                let reading = spans.iter().map(|s| s.text.as_str()).collect::<String>();
+                (spans, reading)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let run_reading =
+        |range: &Range<usize>| reading_chars[range.clone()].iter().collect::<String>();
+
+    // Prefer the alignment that agrees with the most hints.
+    let alignment = Aligner::new(&text_chars, &runs, &reading_chars, |kanji, range| {
+        usize::from(
+            hints[kanji]
+                .as_ref()
+                .is_some_and(|(_, reading)| readings_match(&run_reading(range), reading)),
+        )
+    })
+    .best()?;
+
+    let mut reading_spans = vec![];
+    for ((range, reading_range), hint) in kanji_runs.iter().zip(&alignment).zip(hints) {
+        let reading = run_reading(reading_range);
+        match hint {
+            Some((spans, hint_reading)) if readings_match(&reading, &hint_reading) => {
+                reading_spans.extend(spans.into_iter().cloned());
+            }
+            _ => reading_spans.push(span(
+                range,
+                spell_long_vowels(&reading_chars[reading_range.clone()]),
+            )?),
+        }
+    }
+
+    Some(TextEntry {
+        text: text.to_string(),
+        text_is_common: entry.text_is_common,
+        reading: spelled_reading(text, &reading_spans),
+        reading_is_common: entry.reading_is_common,
+        reading_spans,
+        low_confidence: entry.low_confidence,
+        name_types: entry.name_types.clone(),
+    })
+}
+
+/// Guesses the reading spans of a text that is missing from the dictionary,
+/// given its reading and the known readings of each kanji.
+///
+/// Runs of kanji are split into single kanji if their part of the reading is
+/// made up of known readings; otherwise, the whole run is annotated. Long
+/// vowels as pronounced are spelled out. Returns `None` if the reading
+/// cannot be aligned with the text.
+pub(crate) fn guess<'d>(
+    text: &str,
+    reading: &str,
+    kanji_readings: impl Fn(char) -> &'d [String],
+) -> Option<Vec<ReadingSpan>> {
+    let text_chars = text.chars().collect::<Vec<_>>();
+    let reading_chars = reading.chars().map(hiragana).collect::<Vec<_>>();
+
+    let runs = runs(&text_chars);
+    let kanji_runs = kanji_runs(&runs);
+
+    let split = |kanji: usize, reading_range: &Range<usize>| {
+        split_run(
+            &text_chars[kanji_runs[kanji].clone()],
+            &reading_chars[reading_range.clone()],
+            &kanji_readings,
+        )
+    };
+
+    // Prefer the alignment in which the most runs of kanji can be split.
+    let alignment = Aligner::new(&text_chars, &runs, &reading_chars, |kanji, range| {
+        usize::from(split(kanji, range).is_some())
+    })
+    .best()?;
+    let splits = alignment
+        .iter()
+        .enumerate()
+        .map(|(kanji, range)| split(kanji, range))
+        .collect::<Vec<_>>();
+
+    let mut reading_spans = vec![];
+    for ((range, reading_range), split) in kanji_runs.iter().zip(alignment).zip(splits) {
+        match split {
+            Some(split) => {
+                for (index, kanji_reading) in (range.start..).zip(split) {
+                    reading_spans.push(span(&(index..index + 1), kanji_reading)?);
+                }
+            }
+            None => reading_spans.push(span(
+                range,
+                spell_long_vowels(&reading_chars[reading_range]),
+            )?),
+        }
+    }
+
+    Some(reading_spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::{align, annotate, dictionary, format, select};
+
+    #[test]
+    fn realign() {
+        use dictionary::{tests::entry, TextEntry};
+
+        let tests = [
+            (
+                entry("来る", "くる", &[(0, 0, "く")]),
+                "来ない",
+                "こない",
+                "[来]{こ}ない",
+            ),
+            (
+                entry("来る", "くる", &[(0, 0, "く")]),
+                "来ます",
+                "きます",
+                "[来]{き}ます",
+            ),
+            (
+                entry("為る", "する", &[(0, 0, "す")]),
+                "為た",
+                "した",
+                "[為]{し}た",
+            ),
+            (
+                entry(
+                    "勉強する",
+                    "べんきょうする",
+                    &[(0, 0, "べん"), (1, 1, "きょう")],
+                ),
+                "勉強しよう",
+                "べんきょーしよー",
+                "[勉]{べん}[強]{きょう}しよう",
+            ),
+        ];
+
+        for (entry, text, reading, expected) in tests {
+            let realigned = align::realign(&entry, text, reading).unwrap();
+            let fragment = annotate::AnnotatedTextFragment {
+                text: text.into(),
+                annotations: vec![std::borrow::Cow::Owned(realigned)],
+                verbatim: None,
+            };
+            let actual = annotate::AnnotatedText {
+                fragments: vec![fragment],
+            }
+            .render(&select::heuristic::All, &format::Markdown);
+            assert_eq!(actual, expected);
+        }
+
+        assert!(
+            align::realign(&entry("来る", "くる", &[(0, 0, "く")]), "来ない", "だめ").is_none()
+        );
+
+        // Readings given as pronounced are spelled out.
+        let entry = entry(
+            "勉強する",
+            "べんきょうする",
+            &[(0, 0, "べん"), (1, 1, "きょう")],
+        );
+        let realigned = align::realign(&entry, "勉強しよう", "べんきょーしよー").unwrap();
+        assert_eq!(realigned.reading, "べんきょうしよう");
+        let entry = TextEntry {
+            reading_spans: vec![],
+            ..entry
+        };
+        let realigned = align::realign(&entry, "勉強しよう", "べんきょーしよー").unwrap();
+        assert_eq!(realigned.reading_spans[0].text, "べんきょう");
+    }
+
+    #[test]
+    fn guess() {
+        let known = [
+            ('山', &["さん", "やま"][..]),
+            ('田', &["でん", "た"]),
+            ('太', &["たい", "た"]),
+            ('郎', &["ろう"]),
+            ('見', &["み"]),
+            ('送', &["そう", "おく"]),
+            ('学', &["がく"]),
+            ('校', &["こう"]),
+            ('東', &["とう", "ひがし"]),
+            ('京', &["きょう", "けい"]),
+            ('都', &["と", "みやこ"]),
+            ('庁', &["ちょう"]),
+        ]
+        .map(|(kanji, readings)| {
+            (
+                kanji,
+                readings.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            )
+        });
+        let readings = |kanji| {
+            known
+                .iter()
+                .find(|(k, _)| *k == kanji)
+                .map_or(&[][..], |(_, readings)| readings.as_slice())
+        };
+
+        let tests = [
+            ("山田太郎", "やまだたろう", vec!["やま", "だ", "た", "ろう"]),
+            ("見送り", "みおくり", vec!["み", "おく"]),
+            ("学校", "がっこう", vec!["がっ", "こう"]),
+            ("日本語", "にほんご", vec!["にほんご"]),
+            // Readings as pronounced by the tokenizer
+            (
+                "東京都庁",
+                "とーきょーとちょー",
+                vec!["とう", "きょう", "と", "ちょう"],
+            ),
+            ("提灯", "ちょーちん", vec!["ちょうちん"]),
+        ];
+
+        for (text, reading, expected) in tests {
+            let spans = align::guess(text, reading, readings).unwrap();
+            assert_eq!(
+                spans.iter().map(|s| s.text.as_str()).collect::<Vec<_>>(),
+                expected
+            );
+        }
+
+        let spans = align::guess("東京都庁", "とーきょーとちょー", readings).unwrap();
+        assert_eq!(
+            align::spelled_reading("東京都庁", &spans),
+            "とうきょうとちょう"
+        );
+
+        assert!(align::guess("見送り", "みおくる", readings).is_none());
+
+        // Texts with many runs of kanji can have very many alignments, e.g.
+        // when their kana occur often in the reading.
+        let text = format!("{}田", "田の".repeat(30));
+        let reading = format!("{}た", "たのの".repeat(30));
+        let spans = align::guess(&text, &reading, readings).unwrap();
+        assert_eq!(spans.len(), 31);
+        assert_eq!(spans[0].text, "た");
+    }
+}
@@ -1,43 +1,153 @@
 //! Work with and generate annotations.
 
-use std::{borrow::Cow, cmp::Ordering, vec};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    vec,
+};
 
-use lindera_tokenizer::tokenizer::{Tokenizer, TokenizerConfig};
+use lindera_core::error::LinderaError;
+pub use lindera_core::mode::{Mode as TokenizerMode, Penalty};
+use lindera_dictionary::{DictionaryConfig, DictionaryKind, UserDictionaryConfig};
+use lindera_tokenizer::tokenizer::Tokenizer;
 use wana_kana::ConvertJapanese;
 
 use crate::{
-    dictionary::{Dictionary, TextEntry},
-    format::Format,
+    align::{guess, readings_match, realign, spelled_reading},
+    dictionary::{Lookup, ReadingSpan, TextEntry, TokenizerFormat},
+    document::{AnnotatedDocument, AnnotatedSegment, Document, Segment},
+    format::{Format, Parse},
+    normalize::{self, Normalized},
     select::Select,
 };
 
-fn apply(text_entry: &TextEntry, text: &str, format: &dyn Format) -> String {
-    // assuming the rubies are already sorted
+/// How the readings of a word are rendered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Each reading span is annotated separately (mono ruby), e.g.
+    /// `[計]{けい}[算]{さん}`.
+    #[default]
+    Mono,
+    /// Adjacent reading spans are annotated together (group ruby), e.g.
+    /// `[計算]{けいさん}`.
+    Group,
+    /// Adjacent reading spans are annotated together, but keep their
+    /// individual readings (jukugo ruby), e.g. `[計算]{けい|さん}`.
+    Jukugo,
+}
+
+/// Error type for annotating and rendering text.
+#[derive(Debug, thiserror::Error)]
+pub enum AnnotateError {
+    /// Error tokenizing a text.
+    #[error("Failed to tokenize {text:?}: {source}")]
+    Tokenize {
+        /// The text that could not be tokenized.
+        text: String,
+        /// The error given by the tokenizer.
+        #[source]
+        source: LinderaError,
+    },
+    /// A reading span of an entry does not fit the text of its fragment.
+    #[error(
+        "Invalid reading span {}-{} in entry {}|{} for fragment {fragment:?}",
+        .span.start_index,
+        .span.end_index,
+        .entry.text,
+        .entry.reading
+    )]
+    InvalidSpan {
+        /// The text of the fragment.
+        fragment: String,
+        /// The entry selected for the fragment.
+        entry: Box<TextEntry>,
+        /// The offending span of the entry.
+        span: ReadingSpan,
+    },
+}
+
+/// Appends the text, annotated with the reading spans of the entry, to the
+/// output.
+///
+/// Spans that overlap a preceding span are skipped. Nothing is appended if
+/// a span is out of the bounds of the text.
+fn apply(
+    text_entry: &TextEntry,
+    text: &str,
+    format: &dyn Format,
+    mode: RenderMode,
+    output: &mut String,
+) -> Result<(), AnnotateError> {
+    let fragment = text;
+    // spans are sorted by `dictionary::build`, but may not be in other entries
     let text = text.chars().collect::<Vec<_>>();
-    let (last_index, mut s) = text_entry.reading_spans.iter().fold(
-        (0, String::new()),
-        |(valid_next_index, mut s), span| {
-            let start_index = span.start_index as usize;
-            let end_index = span.end_index as usize;
-            if start_index >= valid_next_index {
-                s.push_str(
-                    &text[valid_next_index..start_index]
-                        .iter()
-                        .collect::<String>(),
-                );
-                let base = &text[start_index..=end_index].iter().collect::<String>();
-                let text = &span.text;
 
-                s.push_str(&format.format(base, text));
-                (end_index + 1, s)
-            } else {
-                (valid_next_index, s)
+    // runs of adjacent spans that are annotated together
+    let mut runs: Vec<Vec<&ReadingSpan>> = vec![];
+    let mut valid_next_index = 0;
+    for span in &text_entry.reading_spans {
+        let start_index = span.start_index as usize;
+        if start_index < valid_next_index {
+            continue;
+        }
+        if span.end_index < span.start_index || span.end_index as usize >= text.len() {
+            return Err(AnnotateError::InvalidSpan {
+                fragment: fragment.to_string(),
+                entry: Box::new(text_entry.clone()),
+                span: span.clone(),
+            });
+        }
+        match runs.last_mut() {
+            Some(run) if mode != RenderMode::Mono && start_index == valid_next_index => {
+                run.push(span);
             }
-        },
-    );
+            _ => runs.push(vec![span]),
+        }
+        valid_next_index = span.end_index as usize + 1;
+    }
+
+    let mut next_index = 0;
+    for run in runs {
+        let start_index = run[0].start_index as usize;
+        output.extend(&text[next_index..start_index]);
 
-    s.push_str(&text[last_index..].iter().collect::<String>());
-    s
+        let bases = run
+            .iter()
+            .map(|span| {
+                text[span.start_index as usize..=span.end_index as usize]
+                    .iter()
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+        let pairs = bases
+            .iter()
+            .zip(&run)
+            .map(|(base, span)| (base.as_str(), span.text.as_str()))
+            .collect::<Vec<_>>();
+
+        let preceding = output.chars().next_back();
+        let formatted = match (mode, pairs.as_slice()) {
+            (_, [(base, reading)]) => format.format_after(preceding, base, reading),
+            (RenderMode::Group, _) => format.format_after(
+                preceding,
+                &bases.concat(),
+                &run.iter()
+                    .map(|span| span.text.as_str())
+                    .collect::<String>(),
+            ),
+            _ => format.format_jukugo(preceding, &pairs),
+        };
+        output.push_str(&formatted);
+
+        next_index = run[run.len() - 1].end_index as usize + 1;
+    }
+
+    output.extend(&text[next_index..]);
+
+    Ok(())
 }
 
 /// A text fragment with annotations. Usually a word or well-known phrase.
@@ -46,7 +156,10 @@ pub struct AnnotatedTextFragment<'a> {
     /// The original text of the fragment.
     pub text: Cow<'a, str>,
     /// The annotations associated with the fragment.
-    pub annotations: Vec<&'a TextEntry>,
+    pub annotations: Vec<Cow<'a, TextEntry>>,
+    /// The original markup of a fragment that was already annotated in the
+    /// input. Such a fragment is locked: it is always rendered verbatim.
+    pub verbatim: Option<Cow<'a, str>>,
 }
 
 impl<'a> AnnotatedTextFragment<'a> {
@@ -56,7 +169,52 @@ impl<'a> AnnotatedTextFragment<'a> {
         Self {
             text,
             annotations: vec![],
+            verbatim: None,
+        }
+    }
+
+    /// Whether the fragment is locked, i.e. rendered verbatim.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.verbatim.is_some()
+    }
+
+    /// Create a new fragment of a word with a single annotation given by
+    /// pairs of base text and reading text.
+    ///
+    /// Returns `None` if the base text is longer than reading spans can index,
+    /// i.e. 256 characters.
+    #[must_use]
+    pub fn from_pairs<S: AsRef<str>>(pairs: &[(S, S)]) -> Option<Self> {
+        let mut text = String::new();
+        let mut reading = String::new();
+        let mut reading_spans = vec![];
+
+        for (base, base_reading) in pairs {
+            let start_index = text.chars().count();
+            text.push_str(base.as_ref());
+            reading.push_str(base_reading.as_ref());
+            let end_index = text.chars().count().saturating_sub(1);
+            reading_spans.push(ReadingSpan {
+                start_index: u8::try_from(start_index).ok()?,
+                end_index: u8::try_from(end_index).ok()?,
+                text: base_reading.as_ref().to_string(),
+            });
         }
+
+        Some(Self {
+            text: text.clone().into(),
+            annotations: vec![Cow::Owned(TextEntry {
+                text,
+                text_is_common: false,
+                reading,
+                reading_is_common: false,
+                reading_spans,
+                low_confidence: false,
+                name_types: vec![],
+            })],
+            verbatim: None,
+        })
     }
 }
 
@@ -68,26 +226,96 @@ pub struct AnnotatedText<'a> {
 }
 
 impl<'a> AnnotatedText<'a> {
-    /// Render the annotated text into a string.
+    /// Render the annotated text into a string, using mono ruby.
+    ///
+    /// Locked fragments are rendered verbatim. They are still passed to the
+    /// selector, so that stateful selectors can take them into account.
+    /// Fragments whose selected entry has invalid reading spans are rendered
+    /// without annotations; use [`AnnotatedText::try_render`] to report them
+    /// instead.
     pub fn render(&'a self, selector: &dyn Select<'a>, format: &dyn Format) -> String {
+        self.render_with_mode(selector, format, RenderMode::Mono)
+    }
+
+    /// Render the annotated text into a string, using the given mode.
+    pub fn render_with_mode(
+        &'a self,
+        selector: &dyn Select<'a>,
+        format: &dyn Format,
+        mode: RenderMode,
+    ) -> String {
         self.fragments
             .iter()
-            .map(move |frag| {
-                let annotation = selector.select(frag);
-                match annotation {
-                    Some(annotation) => apply(annotation, &frag.text, format).into(),
-                    None => frag.text.clone(),
+            .fold(String::new(), |mut output, frag| {
+                if render_fragment(frag, selector, format, mode, &mut output).is_err() {
+                    output.push_str(&frag.text);
                 }
+                output
             })
-            .collect()
     }
+
+    /// Render the annotated text into a string, using mono ruby.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry selected for a fragment has invalid
+    /// reading spans.
+    pub fn try_render(
+        &'a self,
+        selector: &dyn Select<'a>,
+        format: &dyn Format,
+    ) -> Result<String, AnnotateError> {
+        self.try_render_with_mode(selector, format, RenderMode::Mono)
+    }
+
+    /// Render the annotated text into a string, using the given mode.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry selected for a fragment has invalid
+    /// reading spans.
+    pub fn try_render_with_mode(
+        &'a self,
+        selector: &dyn Select<'a>,
+        format: &dyn Format,
+        mode: RenderMode,
+    ) -> Result<String, AnnotateError> {
+        let mut output = String::new();
+        for frag in &self.fragments {
+            render_fragment(frag, selector, format, mode, &mut output)?;
+        }
+        Ok(output)
+    }
+}
+
+/// Appends the fragment, annotated with the entry chosen by the selector, to
+/// the output.
+fn render_fragment<'a>(
+    frag: &'a AnnotatedTextFragment<'a>,
+    selector: &dyn Select<'a>,
+    format: &dyn Format,
+    mode: RenderMode,
+    output: &mut String,
+) -> Result<(), AnnotateError> {
+    let annotation = selector.select(frag);
+    match (&frag.verbatim, annotation) {
+        (Some(verbatim), _) => output.push_str(verbatim),
+        (None, Some(annotation)) => apply(annotation, &frag.text, format, mode, output)?,
+        (None, None) => output.push_str(&frag.text),
+    }
+    Ok(())
 }
 
 #[derive(Clone, Debug)]
 struct InternalToken<'a> {
     pub original_text: Cow<'a, str>,
     pub lookup_text: String,
+    /// The reading of the dictionary form, if known.
     pub reading_hint: Option<String>,
+    /// The reading of the original text, if known.
+    pub surface_reading: Option<String>,
+    /// The text in the input, if normalization changed it.
+    pub surface: Option<&'a str>,
 }
 
 impl<'a> From<&'a str> for InternalToken<'a> {
@@ -97,6 +325,8 @@ impl<'a> From<&'a str> for InternalToken<'a> {
             original_text: value.into(),
             lookup_text,
             reading_hint: None,
+            surface_reading: None,
+            surface: None,
         }
     }
 }
@@ -108,61 +338,336 @@ impl<'a> From<Cow<'a, str>> for InternalToken<'a> {
             original_text: text,
             lookup_text,
             reading_hint: None,
+            surface_reading: None,
+            surface: None,
         }
     }
 }
 
-impl<'a> From<String> for InternalToken<'a> {
+impl From<String> for InternalToken<'_> {
     fn from(text: String) -> Self {
         let lookup_text = text.clone();
         Self {
             original_text: text.into(),
             lookup_text,
             reading_hint: None,
+            surface_reading: None,
+            surface: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Details<'a>(&'a [String]);
+/// The dictionary the tokenizer uses to split text into words.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum TokenizerDictionary {
+    /// The `UniDic` dictionary embedded in lindera.
+    #[default]
+    UniDic,
+    /// A `UniDic` dictionary compiled by lindera, in the given directory.
+    UniDicPath(PathBuf),
+    /// An IPADIC dictionary compiled by lindera, in the given directory.
+    IpadicPath(PathBuf),
+}
 
-impl<'a> TryFrom<&'a [String]> for Details<'a> {
-    type Error = ();
+impl TokenizerDictionary {
+    /// Returns the format of user dictionaries for this tokenizer dictionary,
+    /// e.g. for [`LayeredDictionary::write_tokenizer_dictionary`].
+    ///
+    /// [`LayeredDictionary::write_tokenizer_dictionary`]: crate::dictionary::LayeredDictionary::write_tokenizer_dictionary
+    #[must_use]
+    pub fn user_dictionary_format(&self) -> TokenizerFormat {
+        match self {
+            Self::UniDic | Self::UniDicPath(_) => TokenizerFormat::UniDic,
+            Self::IpadicPath(_) => TokenizerFormat::Ipadic,
+        }
+    }
 
-    fn try_from(value: &'a [String]) -> Result<Self, Self::Error> {
-        if value.len() < 11 {
-            return Err(());
+    fn kind(&self) -> DictionaryKind {
+        match self {
+            Self::UniDic | Self::UniDicPath(_) => DictionaryKind::UniDic,
+            Self::IpadicPath(_) => DictionaryKind::IPADIC,
         }
-        Ok(Self(value))
     }
+
+    fn config(&self) -> DictionaryConfig {
+        match self {
+            Self::UniDic => DictionaryConfig {
+                kind: Some(self.kind()),
+                path: None,
+            },
+            Self::UniDicPath(path) | Self::IpadicPath(path) => DictionaryConfig {
+                kind: None,
+                path: Some(path.clone()),
+            },
+        }
+    }
+}
+
+/// The details of a token given by the tokenizer.
+#[derive(Debug, Clone, Copy)]
+struct Details<'a> {
+    fields: &'a [String],
+    kind: &'a DictionaryKind,
 }
 
 impl<'a> Details<'a> {
-    fn reading_katakana(&'a self) -> &'a str {
-        &self.0[9]
+    /// Returns the details, unless they are missing fields (e.g. for unknown
+    /// words).
+    fn new(fields: &'a [String], kind: &'a DictionaryKind) -> Option<Self> {
+        let len = match kind {
+            DictionaryKind::IPADIC => 9,
+            _ => 11,
+        };
+        (fields.len() >= len).then_some(Self { fields, kind })
     }
 
-    fn dictionary_form(&'a self) -> &'a str {
-        &self.0[10]
+    /// Returns the field at the index, unless it is unknown (`*`), as in
+    /// user dictionaries in the simple three-column format.
+    fn field(self, index: usize) -> Option<&'a str> {
+        self.fields
+            .get(index)
+            .map(String::as_str)
+            .filter(|field| *field != "*")
+    }
+
+    fn reading_katakana(self) -> Option<&'a str> {
+        match self.kind {
+            DictionaryKind::IPADIC => self.field(7).or_else(|| self.field(8)),
+            _ => self.field(9).or_else(|| self.field(6)),
+        }
+    }
+
+    fn dictionary_form(self) -> Option<&'a str> {
+        match self.kind {
+            DictionaryKind::IPADIC => self.field(6),
+            _ => self.field(10),
+        }
+    }
+
+    fn dictionary_form_reading_katakana(self) -> Option<&'a str> {
+        match self.kind {
+            DictionaryKind::IPADIC => None,
+            _ => self.field(11),
+        }
     }
 }
 
 impl<'a> InternalToken<'a> {
-    fn from_token(token_text: &'a str, details: Option<Details<'_>>) -> Self {
+    fn from_token(token_text: Cow<'a, str>, details: Option<Details<'_>>) -> Self {
         Self {
-            original_text: token_text.into(),
             lookup_text: details
-                .map(|d| d.dictionary_form().to_string())
+                .map(|d| d.dictionary_form().unwrap_or(&token_text).to_string())
                 .unwrap_or_default(),
-            reading_hint: details.map(|d| d.reading_katakana().to_hiragana()),
+            reading_hint: details.and_then(|d| {
+                d.dictionary_form_reading_katakana()
+                    .or(d.reading_katakana())
+                    .map(ConvertJapanese::to_hiragana)
+            }),
+            surface_reading: details
+                .and_then(Details::reading_katakana)
+                .map(ConvertJapanese::to_hiragana),
+            original_text: token_text,
+            surface: None,
         }
     }
 }
 
+/// Error type for initializing the tokenizer of an annotator.
+#[derive(Debug, thiserror::Error)]
+pub enum TokenizerError {
+    /// Error loading the tokenizer dictionary.
+    #[error("Failed to load tokenizer dictionary: {0}")]
+    Dictionary(#[source] LinderaError),
+    /// Error loading the tokenizer user dictionary.
+    #[error("Failed to load tokenizer user dictionary {}: {1}", .0.display())]
+    UserDictionary(PathBuf, #[source] LinderaError),
+}
+
+/// A tokenizer that can be shared by many annotators, across threads.
+/// Cloning it is cheap.
+#[derive(Clone)]
+pub struct SharedTokenizer {
+    tokenizer: Arc<Tokenizer>,
+    /// The kind of the tokenizer dictionary, which determines the layout of
+    /// token details.
+    kind: DictionaryKind,
+}
+
+impl SharedTokenizer {
+    /// Load a tokenizer with a dictionary, an optional lindera user
+    /// dictionary (see [`AnnotatorBuilder::user_dictionary`]) and a mode.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tokenizer dictionary or user dictionary cannot
+    /// be loaded.
+    pub fn new(
+        dictionary: &TokenizerDictionary,
+        user_dictionary: Option<PathBuf>,
+        mode: TokenizerMode,
+    ) -> Result<Self, TokenizerError> {
+        let kind = dictionary.kind();
+
+        let tokenizer_dictionary =
+            lindera_dictionary::load_dictionary_from_config(dictionary.config())
+                .map_err(TokenizerError::Dictionary)?;
+
+        let user_dictionary = user_dictionary
+            .map(|path| {
+                lindera_dictionary::load_user_dictionary(UserDictionaryConfig {
+                    path: path.clone(),
+                    kind: Some(kind.clone()),
+                })
+                .map_err(|e| TokenizerError::UserDictionary(path, e))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            tokenizer: Arc::new(Tokenizer::new(tokenizer_dictionary, user_dictionary, mode)),
+            kind,
+        })
+    }
+
+    /// Returns the default tokenizer, with the `UniDic` dictionary embedded
+    /// in lindera in normal mode. It is loaded once, on first use, and shared
+    /// by all annotators that use it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tokenizer dictionary cannot be loaded.
+    pub fn unidic() -> Result<Self, TokenizerError> {
+        static UNIDIC: OnceLock<SharedTokenizer> = OnceLock::new();
+
+        if let Some(tokenizer) = UNIDIC.get() {
+            return Ok(tokenizer.clone());
+        }
+        let tokenizer = Self::new(&TokenizerDictionary::UniDic, None, TokenizerMode::Normal)?;
+        Ok(UNIDIC.get_or_init(|| tokenizer).clone())
+    }
+}
+
+/// Builds an [`Annotator`] with a configurable tokenizer.
+pub struct AnnotatorBuilder<'a> {
+    dictionary: &'a dyn Lookup,
+    tokenizer_dictionary: TokenizerDictionary,
+    user_dictionary: Option<PathBuf>,
+    mode: TokenizerMode,
+    tokenizer: Option<SharedTokenizer>,
+    existing: Option<&'a (dyn Parse + Sync)>,
+    normalize: bool,
+}
+
+impl<'a> AnnotatorBuilder<'a> {
+    /// Create a builder for an annotator with a dictionary. By default, the
+    /// annotator uses [`SharedTokenizer::unidic`].
+    #[must_use]
+    pub fn new(dictionary: &'a dyn Lookup) -> Self {
+        Self {
+            dictionary,
+            tokenizer_dictionary: TokenizerDictionary::default(),
+            user_dictionary: None,
+            mode: TokenizerMode::Normal,
+            tokenizer: None,
+            existing: None,
+            normalize: false,
+        }
+    }
+
+    /// Set the dictionary the tokenizer uses to split text into words.
+    #[must_use]
+    pub fn tokenizer_dictionary(mut self, tokenizer_dictionary: TokenizerDictionary) -> Self {
+        self.tokenizer_dictionary = tokenizer_dictionary;
+        self
+    }
+
+    /// Set a lindera user dictionary that tells the tokenizer which words to
+    /// keep together.
+    ///
+    /// The user dictionary is either a CSV file in the format of the
+    /// tokenizer dictionary (e.g. written by
+    /// [`LayeredDictionary::write_tokenizer_dictionary`](crate::dictionary::LayeredDictionary::write_tokenizer_dictionary)
+    /// in the [`TokenizerDictionary::user_dictionary_format`]) or a `.bin`
+    /// file compiled by lindera.
+    #[must_use]
+    pub fn user_dictionary(mut self, path: impl Into<PathBuf>) -> Self {
+        self.user_dictionary = Some(path.into());
+        self
+    }
+
+    /// Set the tokenizer mode. [`TokenizerMode::Decompose`] splits long
+    /// compounds into shorter words, according to the given penalties.
+    #[must_use]
+    pub fn mode(mut self, mode: TokenizerMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Use an already loaded tokenizer, e.g. one shared with another
+    /// annotator (see [`Annotator::tokenizer`]). Overrides the tokenizer
+    /// dictionary, user dictionary and mode.
+    #[must_use]
+    pub fn tokenizer(mut self, tokenizer: SharedTokenizer) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+
+    /// Keep annotations that already exist in the input in the given format.
+    /// See [`Annotator::preserve_existing`].
+    #[must_use]
+    pub fn preserve_existing(mut self, format: &'a (dyn Parse + Sync)) -> Self {
+        self.existing = Some(format);
+        self
+    }
+
+    /// Set whether orthographic variants are normalized before lookup (off
+    /// by default): full-width ASCII, half-width katakana, old forms of kanji,
+    /// and repeated kanji written with or without `々`. Fragments keep the
+    /// text of the input.
+    #[must_use]
+    pub fn normalize_variants(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Build the annotator.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tokenizer dictionary or user dictionary cannot
+    /// be loaded.
+    pub fn build(self) -> Result<Annotator<'a>, TokenizerError> {
+        let tokenizer = match self.tokenizer {
+            Some(tokenizer) => tokenizer,
+            None if self.tokenizer_dictionary == TokenizerDictionary::UniDic
+                && self.user_dictionary.is_none()
+                && self.mode == TokenizerMode::Normal =>
+            {
+                SharedTokenizer::unidic()?
+            }
+            None => {
+                SharedTokenizer::new(&self.tokenizer_dictionary, self.user_dictionary, self.mode)?
+            }
+        };
+
+        Ok(Annotator {
+            dictionary: self.dictionary,
+            tokenizer,
+            existing: self.existing,
+            normalize: self.normalize,
+        })
+    }
+}
+
 /// Annotates text with readings, given a dictionary.
+///
+/// Annotators are [`Send`] and [`Sync`], so one instance can annotate texts
+/// on many threads at once. Their tokenizer can also be shared with other
+/// annotators, see [`AnnotatorBuilder::tokenizer`].
 pub struct Annotator<'a> {
-    dictionary: &'a Dictionary,
-    tokenizer: Tokenizer,
+    dictionary: &'a dyn Lookup,
+    tokenizer: SharedTokenizer,
+    existing: Option<&'a (dyn Parse + Sync)>,
+    normalize: bool,
 }
 
 impl<'a> Annotator<'a> {
@@ -170,46 +675,71 @@ impl<'a> Annotator<'a> {
     #[cfg(feature = "integrated")]
     #[must_use]
     pub fn new_with_integrated_dictionary() -> Self {
-        Annotator::new(&crate::DICTIONARY)
+        Annotator::new(crate::integrated_dictionary())
     }
 
-    /// Create a new annotator with a dictionary.
+    /// Create a new annotator with a dictionary, e.g. a
+    /// [`Dictionary`](crate::dictionary::Dictionary) or a
+    /// [`LayeredDictionary`](crate::dictionary::LayeredDictionary).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the tokenizer fails to initialize. Use
+    /// [`Annotator::builder`] to handle the error instead.
     #[must_use]
-    pub fn new(dictionary: &'a Dictionary) -> Self {
-        let dictionary_kind = lindera_dictionary::DictionaryKind::UniDic;
+    pub fn new(dictionary: &'a dyn Lookup) -> Self {
+        Self::builder(dictionary)
+            .build()
+            .expect("Failed to initialize tokenizer")
+    }
 
-        let tokenizer = Tokenizer::from_config(TokenizerConfig {
-            dictionary: lindera_dictionary::DictionaryConfig {
-                kind: Some(dictionary_kind),
-                path: None,
-            },
-            user_dictionary: None,
-            mode: lindera_core::mode::Mode::Normal,
-        })
-        .expect("Failed to initialize tokenizer");
+    /// Create a builder for an annotator with a dictionary, to configure its
+    /// tokenizer.
+    #[must_use]
+    pub fn builder(dictionary: &'a dyn Lookup) -> AnnotatorBuilder<'a> {
+        AnnotatorBuilder::new(dictionary)
+    }
 
-        Self {
-            dictionary,
-            tokenizer,
-        }
+    /// Returns the tokenizer of the annotator, to share it with other
+    /// annotators.
+    #[must_use]
+    pub fn tokenizer(&self) -> &SharedTokenizer {
+        &self.tokenizer
+    }
+
+    /// Keep annotations that already exist in the input in the given format.
+    /// They are locked, i.e. rendered verbatim, and are never annotated
+    /// again.
+    #[must_use]
+    pub fn preserve_existing(mut self, format: &'a (dyn Parse + Sync)) -> Self {
+        self.existing = Some(format);
+        self
     }
 
     fn annotate_internal_token<'b>(
         &'b self,
         token: InternalToken<'b>,
     ) -> AnnotatedTextFragment<'b> {
-        let reading_hint = token.reading_hint.as_ref();
+        let reading_hint = token.reading_hint.as_deref();
+        let matches_hint = |reading: &str| reading_hint.is_some_and(|h| readings_match(reading, h));
 
         let mut entries = self
             .dictionary
             .lookup_word(&token.lookup_text)
             .collect::<Vec<_>>();
+        if entries.is_empty() && self.normalize {
+            entries = normalize::variants(&token.lookup_text)
+                .iter()
+                .map(|variant| self.dictionary.lookup_word(variant).collect::<Vec<_>>())
+                .find(|entries| !entries.is_empty())
+                .unwrap_or_default();
+        }
 
         entries.sort_by(|a, b| {
             #[allow(clippy::match_same_arms)] // order-dependent
             match (
-                Some(&a.reading) == reading_hint,
-                Some(&b.reading) == reading_hint,
+                matches_hint(&a.reading),
+                matches_hint(&b.reading),
                 a.reading_is_common,
                 b.reading_is_common,
             ) {
@@ -221,32 +751,192 @@ impl<'a> Annotator<'a> {
             }
         });
 
+        // Entries for the dictionary form of an inflected word must be
+        // realigned with the text, since the reading of the stem may change
+        // (e.g. 来る → 来ない).
+        let surface_reading = token
+            .surface_reading
+            .as_deref()
+            .filter(|_| token.original_text != token.lookup_text);
+
+        let mut annotations = entries
+            .into_iter()
+            .map(|entry| {
+                surface_reading
+                    .and_then(|reading| realign(entry, &token.original_text, reading))
+                    .map_or(Cow::Borrowed(entry), Cow::Owned)
+            })
+            .collect::<Vec<_>>();
+
+        // Words missing from the dictionary are annotated with the reading
+        // given by the tokenizer, split across the text as well as possible.
+        if annotations.is_empty() {
+            annotations.extend(self.guess(&token).map(Cow::Owned));
+        }
+
+        // Normalized text is annotated as it appears in the input, with the
+        // reading spans moved onto its characters.
+        let Some(surface) = token.surface else {
+            return AnnotatedTextFragment {
+                text: token.original_text,
+                annotations,
+                verbatim: None,
+            };
+        };
+        let annotations = annotations
+            .into_iter()
+            .map(|entry| {
+                let mut entry = entry.into_owned();
+                entry.reading_spans = normalize::original_spans(surface, &entry.reading_spans);
+                entry.text = surface.to_string();
+                Cow::Owned(entry)
+            })
+            .collect();
+
         AnnotatedTextFragment {
-            text: token.original_text,
-            annotations: entries,
+            text: surface.into(),
+            annotations,
+            verbatim: None,
+        }
+    }
+
+    /// Returns the key under which texts are compared when joining tokens.
+    fn fold<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        if self.normalize {
+            normalize::fold(text)
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Returns the entries starting with a token, or with its widened form.
+    fn lookup_token<'s>(&'s self, token: &'s str, widened: Option<&'s str>) -> Vec<&'s TextEntry> {
+        let mut entries = self.dictionary.lookup_prefixed(token).collect::<Vec<_>>();
+        if let Some(widened) = widened {
+            entries.extend(self.dictionary.lookup_prefixed(widened));
         }
+        entries
+    }
+
+    /// Guesses a low-confidence entry for a token missing from the
+    /// dictionary, from the reading given by the tokenizer.
+    fn guess(&self, token: &InternalToken<'_>) -> Option<TextEntry> {
+        let reading = token.surface_reading.as_ref()?;
+        let reading_spans = guess(&token.original_text, reading, |kanji| {
+            self.dictionary.kanji_readings(kanji)
+        })
+        .filter(|spans| !spans.is_empty())?;
+
+        Some(TextEntry {
+            text: token.original_text.to_string(),
+            text_is_common: false,
+            reading: spelled_reading(&token.original_text, &reading_spans),
+            reading_is_common: false,
+            reading_spans,
+            low_confidence: true,
+            name_types: vec![],
+        })
     }
 
     /// Annotate a text with readings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the tokenizer fails. Use [`Annotator::try_annotate`] to
+    /// handle the error instead.
     #[must_use]
-    #[allow(clippy::missing_panics_doc)]
     pub fn annotate<'b>(&'b self, text: &'b str) -> AnnotatedText<'b> {
+        self.try_annotate(text).expect("Failed to annotate text")
+    }
+
+    /// Annotate a text with readings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tokenizer fails.
+    pub fn try_annotate<'b>(&'b self, text: &'b str) -> Result<AnnotatedText<'b>, AnnotateError> {
+        let Some(existing) = self.existing else {
+            return self.annotate_unlocked(text);
+        };
+
+        let mut fragments = vec![];
+        for fragment in existing.parse_locked(text).fragments {
+            match (&fragment.text, &fragment.verbatim) {
+                (Cow::Borrowed(text), None) if !text.trim().is_empty() => {
+                    fragments.extend(self.annotate_unlocked(text)?.fragments);
+                }
+                _ => fragments.push(fragment),
+            }
+        }
+
+        Ok(AnnotatedText { fragments })
+    }
+
+    /// Existing annotations in skipped parts of a document (e.g. an HTML
+    /// `<ruby>` element) are still recognized as locked fragments, so that
+    /// selectors can take them into account.
+    fn verbatim_segment<'b>(&self, text: &'b str) -> AnnotatedSegment<'b> {
+        match self.existing.map(|existing| existing.parse_locked(text)) {
+            Some(parsed)
+                if parsed
+                    .fragments
+                    .iter()
+                    .any(AnnotatedTextFragment::is_locked) =>
+            {
+                AnnotatedSegment::Text(parsed)
+            }
+            _ => AnnotatedSegment::Verbatim(text),
+        }
+    }
+
+    /// Normalizes orthographic variants in a text, if enabled and any occur.
+    fn normalized<'t>(&self, text: &'t str) -> Option<Normalized<'t>> {
+        self.normalize
+            .then(|| Normalized::new(text))
+            .filter(Normalized::is_changed)
+    }
+
+    /// Annotate a text without existing annotations.
+    fn annotate_unlocked<'b>(&'b self, text: &'b str) -> Result<AnnotatedText<'b>, AnnotateError> {
         if text.trim().is_empty() {
-            return AnnotatedText::default();
+            return Ok(AnnotatedText::default());
         }
 
-        let mut tokens = self.tokenizer.tokenize(text).unwrap(); // this function is actually infallible. Not sure why it returns a Result
+        // Variants are normalized before tokenizing, and mapped back to the
+        // input for each token.
+        let normalized = self.normalized(text);
+        let tokenized = normalized.as_ref().map_or(text, |n| n.text.as_ref());
+        let token_text = |range: Range<usize>| match &normalized {
+            Some(normalized) => normalized.slice(range),
+            None => (Cow::Borrowed(&text[range]), None),
+        };
 
-        // tokens must have at least one element
+        let mut tokens = self
+            .tokenizer
+            .tokenizer
+            .tokenize(tokenized)
+            .map_err(|source| AnnotateError::Tokenize {
+                text: text.to_string(),
+                source,
+            })?;
+
+        let Some(first_token) = tokens.first() else {
+            return Ok(AnnotatedText {
+                fragments: vec![AnnotatedTextFragment::plain(text.into())],
+            });
+        };
+
+        // Dictionaries such as JMdict write ASCII in full-width.
+        let widened = tokens
+            .iter()
+            .map(|t| self.normalize.then(|| normalize::widen(t.text)).flatten())
+            .collect::<Vec<_>>();
 
         let mut internal_tokens: Vec<InternalToken<'b>> = vec![];
         let mut token_buffer_start: usize = 0;
         // Exclusive upper bound
         let mut token_buffer_end: usize = 1;
-        let mut buffer_possibilities = self
-            .dictionary
-            .lookup_prefixed(tokens[0].text)
-            .collect::<Vec<_>>();
+        let mut buffer_possibilities = self.lookup_token(first_token.text, widened[0].as_deref());
 
         while token_buffer_start < tokens.len() {
             // remember: exclusive upper bound
@@ -258,10 +948,11 @@ impl<'a> Annotator<'a> {
                     .iter()
                     .map(|t| t.text)
                     .collect::<String>();
+                let current_substring = self.fold(&current_substring);
 
                 buffer_possibilities
                     .iter()
-                    .any(|p| p.text.starts_with(&current_substring))
+                    .any(|p| self.fold(&p.text).starts_with(&*current_substring))
             };
 
             if next_token_exists && possibilities_remain() {
@@ -275,8 +966,12 @@ impl<'a> Annotator<'a> {
                         .iter()
                         .map(|t| t.text)
                         .collect::<String>();
+                    let substring = self.fold(&substring);
 
-                    if buffer_possibilities.iter().any(|p| p.text == substring) {
+                    if buffer_possibilities
+                        .iter()
+                        .any(|p| self.fold(&p.text) == substring)
+                    {
                         break;
                     }
                     longest_possibility_end -= 1;
@@ -292,28 +987,30 @@ impl<'a> Annotator<'a> {
                     // The number of tokens that match a suggestion is 0 or 1.
                     // That is, we cannot generate readings for a longer text fragment.
                     let t = &mut tokens[token_buffer_start];
+                    let (token_text, surface) = token_text(t.byte_start..t.byte_end);
                     let details = t
                         .get_details()
                         .map(|v| v.into_iter().map(ToString::to_string).collect::<Vec<_>>());
-                    let internal_token = details.map_or_else(
-                        || t.text.into(),
-                        |details| {
-                            InternalToken::from_token(
-                                t.text,
-                                Details::try_from(details.as_ref()).ok(),
-                            )
-                        },
-                    );
+                    let mut internal_token = match details {
+                        Some(d) => InternalToken::from_token(
+                            token_text,
+                            Details::new(&d, &self.tokenizer.kind),
+                        ),
+                        None => token_text.into(),
+                    };
+                    internal_token.surface = surface;
                     internal_tokens.push(internal_token);
                     token_buffer_start += 1;
                 } else {
                     // We can concatenate two or more tokens together to create
                     // a longer text fragment, for which we know readings exist.
-                    let substring = tokens[token_buffer_start..longest_possibility_end]
-                        .iter()
-                        .map(|t| t.text)
-                        .collect::<String>();
-                    internal_tokens.push(substring.into());
+                    let (substring, surface) = token_text(
+                        tokens[token_buffer_start].byte_start
+                            ..tokens[longest_possibility_end - 1].byte_end,
+                    );
+                    let mut internal_token = InternalToken::from(substring);
+                    internal_token.surface = surface;
+                    internal_tokens.push(internal_token);
                     token_buffer_start = longest_possibility_end;
                 }
 
@@ -321,16 +1018,61 @@ impl<'a> Annotator<'a> {
                 token_buffer_end = token_buffer_start + 1;
 
                 if let Some(t) = tokens.get(token_buffer_start) {
-                    buffer_possibilities = self.dictionary.lookup_prefixed(t.text).collect();
+                    buffer_possibilities =
+                        self.lookup_token(t.text, widened[token_buffer_start].as_deref());
                 }
             }
         }
 
-        AnnotatedText {
+        Ok(AnnotatedText {
             fragments: internal_tokens
                 .into_iter()
                 .map(|internal_token| self.annotate_internal_token(internal_token))
                 .collect(),
-        }
+        })
+    }
+
+    /// Annotate a document with readings. Only the segments of the document
+    /// that contain prose are annotated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the tokenizer fails. Use [`Annotator::try_annotate_document`]
+    /// to handle the error instead.
+    #[must_use]
+    pub fn annotate_document<'b>(
+        &'b self,
+        input: &'b str,
+        document: &dyn Document,
+    ) -> AnnotatedDocument<'b> {
+        self.try_annotate_document(input, document)
+            .expect("Failed to annotate document")
+    }
+
+    /// Annotate a document with readings. Only the segments of the document
+    /// that contain prose are annotated.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tokenizer fails.
+    pub fn try_annotate_document<'b>(
+        &'b self,
+        input: &'b str,
+        document: &dyn Document,
+    ) -> Result<AnnotatedDocument<'b>, AnnotateError> {
+        Ok(AnnotatedDocument {
+            segments: document
+                .segments(input)
+                .into_iter()
+                .map(|segment| match segment {
+                    Segment::Text(text) if !text.trim().is_empty() => {
+                        self.try_annotate(text).map(AnnotatedSegment::Text)
+                    }
+                    Segment::Text(text) | Segment::Verbatim(text) => {
+                        Ok(self.verbatim_segment(text))
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+        })
     }
 }
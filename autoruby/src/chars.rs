@@ -0,0 +1,11 @@
+/// Whether the character is a kanji, or is treated as one in running text
+/// (e.g. the iteration mark `々`).
+pub fn is_kanji(c: char) -> bool {
+    matches!(c,
+        '\u{3400}'..='\u{4DBF}' // CJK Unified Ideographs Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+        | '\u{20000}'..='\u{3134F}' // CJK Unified Ideographs Extension B-G
+        | '々' | '〆' | '〇' | 'ヶ'
+    )
+}
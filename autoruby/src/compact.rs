@@ -0,0 +1,680 @@
+//! A compact dictionary format that is queried in place.
+//!
+//! The format is a sorted string table: a header, a table of offsets to the
+//! entries (sorted by text, then reading), a table of offsets to the known
+//! readings of each kanji (sorted by kanji), followed by the records. All
+//! integers are little-endian; strings are prefixed with their byte length
+//! as a `u16`.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Write},
+    path::Path,
+    sync::OnceLock,
+};
+
+use crate::dictionary::{
+    group_by_reading, normalize_reading, Dictionary, Index, LoadError, Lookup, NameType,
+    ReadingSpan, TextEntry,
+};
+
+const MAGIC: &[u8; 8] = b"AUTORUBY";
+const VERSION: u32 = 1;
+/// Magic, version, entry count and kanji count.
+const HEADER_LEN: usize = 20;
+
+/// Name types by their code in the format.
+const NAME_TYPES: [NameType; 6] = [
+    NameType::Surname,
+    NameType::GivenName,
+    NameType::Person,
+    NameType::Place,
+    NameType::Company,
+    NameType::Other,
+];
+
+/// Reads values from a byte slice.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], position: usize) -> Self {
+        Self { bytes, position }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position.checked_add(len)?)?;
+        self.position += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u16()?;
+        std::str::from_utf8(self.take(len as usize)?).ok()
+    }
+
+    fn entry(&mut self) -> Option<TextEntry> {
+        let text = self.str()?.to_string();
+        let reading = self.str()?.to_string();
+        let flags = self.u8()?;
+
+        let reading_spans = (0..self.u8()?)
+            .map(|_| {
+                Some(ReadingSpan {
+                    start_index: self.u8()?,
+                    end_index: self.u8()?,
+                    text: self.str()?.to_string(),
+                })
+            })
+            .collect::<Option<_>>()?;
+
+        let name_types = (0..self.u8()?)
+            .map(|_| NAME_TYPES.get(self.u8()? as usize).copied())
+            .collect::<Option<_>>()?;
+
+        Some(TextEntry {
+            text,
+            text_is_common: flags & 1 != 0,
+            reading,
+            reading_is_common: flags & 2 != 0,
+            reading_spans,
+            low_confidence: flags & 4 != 0,
+            name_types,
+        })
+    }
+
+    fn readings(&mut self) -> Option<Vec<String>> {
+        (0..self.u8()?)
+            .map(|_| self.str().map(ToString::to_string))
+            .collect()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u8(output: &mut Vec<u8>, value: usize) -> io::Result<()> {
+    output.push(u8::try_from(value).map_err(|_| invalid_data("too many items"))?);
+    Ok(())
+}
+
+fn write_u32(output: &mut Vec<u8>, value: usize) -> io::Result<()> {
+    let value = u32::try_from(value).map_err(|_| invalid_data("dictionary too large"))?;
+    output.extend(value.to_le_bytes());
+    Ok(())
+}
+
+fn write_str(output: &mut Vec<u8>, value: &str) -> io::Result<()> {
+    let len = u16::try_from(value.len()).map_err(|_| invalid_data("string too long"))?;
+    output.extend(len.to_le_bytes());
+    output.extend(value.as_bytes());
+    Ok(())
+}
+
+fn write_entry(output: &mut Vec<u8>, entry: &TextEntry) -> io::Result<()> {
+    write_str(output, &entry.text)?;
+    write_str(output, &entry.reading)?;
+    output.push(
+        u8::from(entry.text_is_common)
+            | u8::from(entry.reading_is_common) << 1
+            | u8::from(entry.low_confidence) << 2,
+    );
+
+    write_u8(output, entry.reading_spans.len())?;
+    for span in &entry.reading_spans {
+        output.extend([span.start_index, span.end_index]);
+        write_str(output, &span.text)?;
+    }
+
+    write_u8(output, entry.name_types.len())?;
+    for name_type in &entry.name_types {
+        write_u8(
+            output,
+            NAME_TYPES.iter().position(|n| n == name_type).unwrap_or(5),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes entries, sorted by text and then reading, and the known readings
+/// of each kanji in the compact format.
+pub(crate) fn write<'e>(
+    entries: impl Iterator<Item = &'e TextEntry>,
+    kanji_readings: &HashMap<char, Vec<String>>,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    let mut records = vec![];
+
+    let mut entry_offsets = vec![];
+    for entry in entries {
+        entry_offsets.push(records.len());
+        write_entry(&mut records, entry)?;
+    }
+
+    let mut kanji = kanji_readings.iter().collect::<Vec<_>>();
+    kanji.sort_by_key(|(kanji, _)| **kanji);
+    let mut kanji_offsets = vec![];
+    for (&kanji, readings) in kanji {
+        kanji_offsets.push((kanji, records.len()));
+        let readings = &readings[..readings.len().min(u8::MAX as usize)];
+        write_u8(&mut records, readings.len())?;
+        for reading in readings {
+            write_str(&mut records, reading)?;
+        }
+    }
+
+    let mut header = MAGIC.to_vec();
+    header.extend(VERSION.to_le_bytes());
+    write_u32(&mut header, entry_offsets.len())?;
+    write_u32(&mut header, kanji_offsets.len())?;
+    for offset in entry_offsets {
+        write_u32(&mut header, offset)?;
+    }
+    for (kanji, offset) in kanji_offsets {
+        write_u32(&mut header, kanji as usize)?;
+        write_u32(&mut header, offset)?;
+    }
+
+    writer.write_all(&header)?;
+    writer.write_all(&records)
+}
+
+/// The bytes of a compact dictionary.
+enum Bytes {
+    Static(&'static [u8]),
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl Bytes {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Static(bytes) => bytes,
+            Self::Owned(bytes) => bytes,
+            #[cfg(feature = "mmap")]
+            Self::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+/// A dictionary in the compact format, queried in place.
+///
+/// Unlike a [`Dictionary`], it does not need to be deserialized before use:
+/// loading it only checks the header and the bounds of its tables, and
+/// entries are decoded on first lookup and cached. Records are checked when
+/// they are read: entries that cannot be decoded are skipped, and lookups
+/// that cannot search past a corrupt record find nothing. Use
+/// [`CompactDictionary::validate`] to check every record up front. Write one
+/// with [`Dictionary::write_compact`].
+pub struct CompactDictionary {
+    bytes: Bytes,
+    entry_count: usize,
+    /// Decoded entries, by their position in the table. The table itself is
+    /// allocated on first use.
+    entries: OnceLock<Box<[OnceLock<Box<TextEntry>>]>>,
+    kanji_count: usize,
+    records_start: usize,
+    /// Decoded readings, by the position of their kanji in the table. The
+    /// table itself is allocated on first use.
+    kanji_readings: OnceLock<Box<[OnceLock<Vec<String>>]>>,
+    /// Indices of entries by normalized reading, common entries first.
+    readings: OnceLock<BTreeMap<String, Vec<usize>>>,
+}
+
+impl fmt::Debug for CompactDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompactDictionary")
+            .field("entries", &self.entry_count)
+            .field("kanji", &self.kanji_count)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CompactDictionary {
+    fn new(bytes: Bytes) -> Result<Self, LoadError> {
+        let slice = bytes.as_slice();
+        let mut header = Reader::new(slice, 0);
+
+        if header.take(MAGIC.len()) != Some(MAGIC) {
+            return Err(LoadError::Compact("not a compact dictionary"));
+        }
+        if header.u32() != Some(VERSION) {
+            return Err(LoadError::Compact("unsupported version"));
+        }
+        let (Some(entry_count), Some(kanji_count)) = (header.u32(), header.u32()) else {
+            return Err(LoadError::Compact("truncated header"));
+        };
+        let (entry_count, kanji_count) = (entry_count as usize, kanji_count as usize);
+
+        let records_start = entry_count
+            .checked_mul(4)
+            .zip(kanji_count.checked_mul(8))
+            .and_then(|(entries, kanji)| HEADER_LEN.checked_add(entries)?.checked_add(kanji))
+            .filter(|&start| start <= slice.len())
+            .ok_or(LoadError::Compact("truncated offset tables"))?;
+
+        Ok(Self {
+            bytes,
+            entry_count,
+            entries: OnceLock::new(),
+            kanji_count,
+            records_start,
+            kanji_readings: OnceLock::new(),
+            readings: OnceLock::new(),
+        })
+    }
+
+    /// Checks that every record can be decoded, so that no entry is skipped
+    /// and every lookup can find its entries. This reads the whole
+    /// dictionary, unlike loading it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a record is invalid.
+    pub fn validate(&self) -> Result<(), LoadError> {
+        let slice = self.bytes.as_slice();
+        let valid_entries = (0..self.entry_count).all(|index| {
+            self.record(index).is_some_and(|mut record| {
+                record.str().is_some()
+                    && record.str().is_some()
+                    && record.u8().is_some()
+                    && record.u8().is_some_and(|spans| {
+                        (0..spans).all(|_| record.take(2).is_some() && record.str().is_some())
+                    })
+                    && record.u8().is_some_and(|names| {
+                        (0..names)
+                            .all(|_| record.u8().is_some_and(|n| (n as usize) < NAME_TYPES.len()))
+                    })
+            })
+        });
+        let valid_kanji = (0..self.kanji_count).all(|index| {
+            let mut table = self.kanji_row(index);
+            table.u32().and_then(char::from_u32).is_some()
+                && table.u32().is_some_and(|offset| {
+                    self.records_start
+                        .checked_add(offset as usize)
+                        .is_some_and(|start| Reader::new(slice, start).readings().is_some())
+                })
+        });
+
+        if valid_entries && valid_kanji {
+            Ok(())
+        } else {
+            Err(LoadError::Compact("invalid record"))
+        }
+    }
+
+    /// Reads a compact dictionary from static bytes, e.g. from
+    /// [`include_bytes!`], without copying them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bytes are not a compact dictionary, or its
+    /// tables are truncated.
+    pub fn from_static(bytes: &'static [u8]) -> Result<Self, LoadError> {
+        Self::new(Bytes::Static(bytes))
+    }
+
+    /// Reads a compact dictionary from bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bytes are not a compact dictionary, or its
+    /// tables are truncated.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, LoadError> {
+        Self::new(Bytes::Owned(bytes))
+    }
+
+    /// Opens a compact dictionary file. With the `mmap` feature, the file is
+    /// memory-mapped rather than read; it must not be modified while the
+    /// dictionary is in use.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not a compact
+    /// dictionary, or its tables are truncated.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        #[cfg(feature = "mmap")]
+        {
+            let file = std::fs::File::open(path)?;
+            // SAFETY: the file is only read, and callers must not modify it
+            // while it is mapped, as documented above.
+            let mmap = unsafe { memmap2::Mmap::map(&file)? };
+            Self::new(Bytes::Mapped(mmap))
+        }
+
+        #[cfg(not(feature = "mmap"))]
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    /// Returns the number of entries in the dictionary.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entry_count
+    }
+
+    /// Whether the dictionary has no entries.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entry_count == 0
+    }
+
+    /// Returns an iterator over all entries, sorted by text and then reading.
+    pub fn entries(&self) -> impl Iterator<Item = &TextEntry> {
+        (0..self.entry_count).filter_map(|index| self.entry(index))
+    }
+
+    /// Returns an iterator over all entries with a given reading, common
+    /// entries first. Readings are compared in hiragana, so the reading may
+    /// be given in hiragana or katakana.
+    pub fn lookup_reading<'s>(&'s self, reading: &str) -> impl Iterator<Item = &'s TextEntry> {
+        self.readings_map()
+            .get(&normalize_reading(reading))
+            .into_iter()
+            .flatten()
+            .filter_map(|&index| self.entry(index))
+    }
+
+    /// Returns an iterator over all entries whose reading starts with a
+    /// given prefix, by reading and then common entries first. Readings are
+    /// compared in hiragana.
+    pub fn lookup_reading_prefixed<'s>(
+        &'s self,
+        prefix: &str,
+    ) -> impl Iterator<Item = &'s TextEntry> {
+        let prefix = normalize_reading(prefix);
+        self.readings_map()
+            .range(prefix.clone()..)
+            .take_while(move |(reading, _)| reading.starts_with(&prefix))
+            .flat_map(|(_, indices)| indices)
+            .filter_map(|&index| self.entry(index))
+    }
+
+    /// Returns the indices of entries by normalized reading, reading only
+    /// the reading and flags of each record.
+    fn readings_map(&self) -> &BTreeMap<String, Vec<usize>> {
+        self.readings.get_or_init(|| {
+            group_by_reading((0..self.entry_count).filter_map(|index| {
+                let mut record = self.record(index)?;
+                record.str()?;
+                let reading = record.str()?;
+                let common = record.u8()? & 3 != 0;
+                Some((index, reading, common))
+            }))
+        })
+    }
+
+    /// Returns a reader at the record of the entry at the index.
+    fn record(&self, index: usize) -> Option<Reader<'_>> {
+        let slice = self.bytes.as_slice();
+        let offset = Reader::new(slice, HEADER_LEN + index * 4).u32()?;
+        Some(Reader::new(
+            slice,
+            self.records_start.checked_add(offset as usize)?,
+        ))
+    }
+
+    /// Returns the text of the entry at the index, without decoding the
+    /// entry.
+    fn text(&self, index: usize) -> Option<&str> {
+        self.record(index)?.str()
+    }
+
+    /// Returns the entry at the index, decoding it on first use.
+    fn entry(&self, index: usize) -> Option<&TextEntry> {
+        let slot = self
+            .entries
+            .get_or_init(|| (0..self.entry_count).map(|_| OnceLock::new()).collect())
+            .get(index)?;
+        if let Some(entry) = slot.get() {
+            return Some(entry);
+        }
+        let entry = self.record(index)?.entry()?;
+        Some(slot.get_or_init(|| Box::new(entry)))
+    }
+
+    /// Returns the index of the first entry whose text is not less than
+    /// `word`, or `None` if the search reads a corrupt record.
+    fn lower_bound(&self, word: &str) -> Option<usize> {
+        let (mut low, mut high) = (0, self.entry_count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.text(middle)? < word {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        Some(low)
+    }
+
+    /// Returns the indices of the entries from the first whose text is not
+    /// less than `word`, while their text satisfies the predicate.
+    fn indices_from<'s>(
+        &'s self,
+        word: &str,
+        predicate: impl 's + Fn(&str) -> bool,
+    ) -> impl 's + Iterator<Item = usize> {
+        self.lower_bound(word)
+            .into_iter()
+            .flat_map(|start| start..self.entry_count)
+            .map_while(move |index| {
+                self.text(index)
+                    .filter(|text| predicate(text))
+                    .map(|_| index)
+            })
+    }
+
+    /// Returns a reader at the kanji table row at the index.
+    fn kanji_row(&self, index: usize) -> Reader<'_> {
+        Reader::new(
+            self.bytes.as_slice(),
+            HEADER_LEN + self.entry_count * 4 + index * 8,
+        )
+    }
+
+    /// Returns the index of the kanji in the kanji table, or `None` if it is
+    /// not there or the search reads a corrupt row.
+    fn kanji_slot(&self, kanji: char) -> Option<usize> {
+        let kanji = u32::from(kanji);
+        let (mut low, mut high) = (0, self.kanji_count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.kanji_row(middle).u32()?.cmp(&kanji) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+
+    /// Returns the readings of the kanji at the index, decoding them on
+    /// first use.
+    fn kanji_entry(&self, index: usize) -> Option<&Vec<String>> {
+        let slot = self
+            .kanji_readings
+            .get_or_init(|| (0..self.kanji_count).map(|_| OnceLock::new()).collect())
+            .get(index)?;
+        if let Some(readings) = slot.get() {
+            return Some(readings);
+        }
+        let mut row = self.kanji_row(index);
+        row.u32()?;
+        let start = self.records_start.checked_add(row.u32()? as usize)?;
+        let readings = Reader::new(self.bytes.as_slice(), start).readings()?;
+        Some(slot.get_or_init(|| readings))
+    }
+
+    /// Decodes the whole dictionary.
+    pub(crate) fn to_dictionary(&self) -> Dictionary {
+        Dictionary::new(
+            (0..self.entry_count)
+                .filter_map(|index| self.record(index)?.entry())
+                .map(|entry| {
+                    let index = Index {
+                        text: entry.text.clone(),
+                        reading: entry.reading.clone(),
+                    };
+                    (index, entry)
+                })
+                .collect::<BTreeMap<_, _>>(),
+        )
+    }
+}
+
+impl Lookup for CompactDictionary {
+    fn lookup_word<'s: 'w, 'w>(
+        &'s self,
+        word: &'w str,
+    ) -> Box<dyn 'w + Iterator<Item = &'s TextEntry>> {
+        Box::new(
+            self.indices_from(word, move |text| text == word)
+                .filter_map(move |index| self.entry(index)),
+        )
+    }
+
+    fn lookup_prefixed<'s>(
+        &'s self,
+        prefix: &'s str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        Box::new(
+            self.indices_from(prefix, move |text| text.starts_with(prefix))
+                .filter_map(move |index| self.entry(index)),
+        )
+    }
+
+    fn lookup_reading<'s>(&'s self, reading: &str) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        Box::new(CompactDictionary::lookup_reading(self, reading))
+    }
+
+    fn lookup_reading_prefixed<'s>(
+        &'s self,
+        prefix: &str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        Box::new(CompactDictionary::lookup_reading_prefixed(self, prefix))
+    }
+
+    fn kanji_readings(&self, kanji: char) -> &[String] {
+        self.kanji_slot(kanji)
+            .and_then(|index| self.kanji_entry(index))
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::dictionary;
+
+    #[test]
+    fn compact_dictionary() {
+        use dictionary::{CompactDictionary, DictionaryFormat, Lookup};
+
+        let source = "\
+漢字|かんじ|0:かん;1:じ
+漢字|かんし|0:かん;1:し
+漢字検定|かんじけんてい|0:かん;1:じ;2:けん;3:てい
+日本|にほん|0-1:にほん
+日本|にっぽん|0-1:にっぽん
+";
+        let dictionary = dictionary::build(source.as_bytes()).unwrap();
+        let mut bytes = vec![];
+        dictionary.write_compact(&mut bytes).unwrap();
+        let compact = CompactDictionary::from_bytes(bytes.clone()).unwrap();
+
+        assert_eq!(compact.len(), 5);
+        let readings = |dictionary: &dyn Lookup, word| {
+            dictionary
+                .lookup_word(word)
+                .map(|e| e.reading.clone())
+                .collect::<Vec<_>>()
+        };
+        for word in ["漢字", "日本", "漢", "字", "東京"] {
+            assert_eq!(readings(&compact, word), readings(&dictionary, word));
+        }
+        assert_eq!(
+            compact
+                .lookup_prefixed("漢字")
+                .map(|e| e.text.as_str())
+                .collect::<Vec<_>>(),
+            ["漢字", "漢字", "漢字検定"],
+        );
+        let spans = |dictionary: &dyn Lookup| {
+            dictionary.lookup_word("漢字検定").next().map(|e| {
+                e.reading_spans
+                    .iter()
+                    .map(|s| (s.start_index, s.end_index, s.text.clone()))
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(spans(&compact), spans(&dictionary));
+        assert_eq!(compact.kanji_readings('漢'), ["かん"]);
+        assert!(compact.kanji_readings('東').is_empty());
+        for kanji in ['字', '検', '定', '漢', '日', '一'] {
+            assert_eq!(
+                compact.kanji_readings(kanji),
+                dictionary.kanji_readings(kanji)
+            );
+        }
+
+        let decoded =
+            dictionary::Dictionary::from_reader(&bytes[..], DictionaryFormat::Compact).unwrap();
+        assert_eq!(readings(&decoded, "日本"), readings(&dictionary, "日本"));
+        assert_eq!(
+            DictionaryFormat::from_path("dict.ardc"),
+            DictionaryFormat::Compact
+        );
+
+        assert!(compact.validate().is_ok());
+
+        // Records are only checked when read, or when validated.
+        let truncated = CompactDictionary::from_bytes(bytes[..bytes.len() - 1].to_vec()).unwrap();
+        assert!(truncated.validate().is_err());
+        assert_eq!(readings(&truncated, "日本"), readings(&dictionary, "日本"));
+        assert!(dictionary::Dictionary::from_reader(
+            &bytes[..bytes.len() - 1],
+            DictionaryFormat::Compact
+        )
+        .is_err());
+        // A corrupt record is skipped, and a search through it finds nothing
+        // rather than the wrong entries.
+        let mut corrupt = bytes.clone();
+        let kanji_count = u32::from_le_bytes(corrupt[16..20].try_into().unwrap()) as usize;
+        let offset = u32::from_le_bytes(corrupt[28..32].try_into().unwrap()) as usize;
+        let record = 40 + kanji_count * 8 + offset;
+        corrupt[record..record + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        let corrupt = CompactDictionary::from_bytes(corrupt).unwrap();
+        assert!(corrupt.validate().is_err());
+        assert_eq!(corrupt.entries().count(), 4);
+        assert!(readings(&corrupt, "漢字").is_empty());
+
+        assert!(CompactDictionary::from_bytes(bytes[..24].to_vec()).is_err());
+        assert!(CompactDictionary::from_bytes(b"AUTORUBY".to_vec()).is_err());
+        assert!(CompactDictionary::from_bytes(vec![]).is_err());
+    }
+}
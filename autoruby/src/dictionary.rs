@@ -1,8 +1,23 @@
 //! Dictionary data structures and parsing.
 
-use std::{collections::BTreeMap, io::BufRead};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::OnceLock,
+};
 
-use crate::parse::{self, dictionary_line};
+use wana_kana::ConvertJapanese;
+
+pub use crate::compact::CompactDictionary;
+use crate::{
+    align,
+    chars::is_kanji,
+    compact,
+    parse::{self, dictionary_line},
+};
 
 /// The URL to download the dictionary from.
 pub const DOWNLOAD_URL: &str =
@@ -41,7 +56,7 @@ pub fn frequency_entries() -> impl Iterator<Item = FrequencyEntry<'static>> {
 pub struct ReadingSpan {
     /// The index of the first character of the substring.
     pub start_index: u8,
-    /// The end index (exclusive) of the substring.
+    /// The index of the last character of the substring (inclusive).
     pub end_index: u8,
     /// The reading text.
     pub text: String,
@@ -57,6 +72,61 @@ impl From<parse::ReadingSpan<'_>> for ReadingSpan {
     }
 }
 
+/// The type of a proper noun. It is serialized as its `JMnedict` entity, as
+/// returned by [`NameType::as_str`].
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NameType {
+    /// A family name.
+    #[cfg_attr(feature = "serde", serde(rename = "surname"))]
+    Surname,
+    /// A given name.
+    #[cfg_attr(feature = "serde", serde(rename = "given"))]
+    GivenName,
+    /// A full name of a particular person.
+    #[cfg_attr(feature = "serde", serde(rename = "person"))]
+    Person,
+    /// A place name, including stations.
+    #[cfg_attr(feature = "serde", serde(rename = "place"))]
+    Place,
+    /// A company or organization name.
+    #[cfg_attr(feature = "serde", serde(rename = "company"))]
+    Company,
+    /// Any other name, e.g. of a product or work.
+    #[cfg_attr(feature = "serde", serde(rename = "oth"))]
+    Other,
+}
+
+impl NameType {
+    /// Returns the type for a `JMnedict` `name_type` entity, e.g. `place` for
+    /// `&place;`.
+    #[must_use]
+    pub fn from_jmnedict(entity: &str) -> Self {
+        match entity {
+            "surname" => Self::Surname,
+            "given" | "masc" | "fem" => Self::GivenName,
+            "person" => Self::Person,
+            "place" | "station" => Self::Place,
+            "company" | "organization" => Self::Company,
+            _ => Self::Other,
+        }
+    }
+
+    /// Returns the `JMnedict` `name_type` entity for the type, as read back
+    /// by [`NameType::from_jmnedict`].
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Surname => "surname",
+            Self::GivenName => "given",
+            Self::Person => "person",
+            Self::Place => "place",
+            Self::Company => "company",
+            Self::Other => "oth",
+        }
+    }
+}
+
 /// A dictionary entry, including reading and frequency data.
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
@@ -71,14 +141,20 @@ pub struct TextEntry {
     pub reading_is_common: bool,
     /// The readings associated with each substring of the word.
     pub reading_spans: Vec<ReadingSpan>,
+    /// Whether the readings were guessed rather than looked up, e.g. for
+    /// words missing from the dictionary.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub low_confidence: bool,
+    /// The types of proper noun the entry is, if any.
+    pub name_types: Vec<NameType>,
 }
 
 /// Dictionary index.
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
 pub struct Index {
-    text: String,
-    reading: String,
+    pub(crate) text: String,
+    pub(crate) reading: String,
 }
 
 impl<T: AsRef<str>> From<T> for Index {
@@ -93,25 +169,965 @@ impl<T: AsRef<str>> From<T> for Index {
 /// A dictionary of words and their readings.
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
-pub struct Dictionary(BTreeMap<Index, TextEntry>);
+pub struct Dictionary {
+    entries: BTreeMap<Index, TextEntry>,
+    /// Known readings of single kanji, derived from the entries on first use.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    kanji_readings: OnceLock<HashMap<char, Vec<String>>>,
+    /// Entries by normalized reading, common entries first, built on the
+    /// first lookup by reading.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    readings: OnceLock<BTreeMap<String, Vec<Index>>>,
+}
+
+/// Groups the keys of entries by normalized reading, common entries first
+/// and otherwise in the given order.
+pub(crate) fn group_by_reading<'r, K>(
+    entries: impl IntoIterator<Item = (K, &'r str, bool)>,
+) -> BTreeMap<String, Vec<K>> {
+    let mut readings: BTreeMap<String, Vec<(bool, K)>> = BTreeMap::new();
+    for (index, reading, common) in entries {
+        readings
+            .entry(normalize_reading(reading))
+            .or_default()
+            .push((!common, index));
+    }
+    readings
+        .into_iter()
+        .map(|(reading, mut indices)| {
+            indices.sort_by_key(|(uncommon, _)| *uncommon);
+            (
+                reading,
+                indices.into_iter().map(|(_, index)| index).collect(),
+            )
+        })
+        .collect()
+}
 
 impl Dictionary {
+    pub(crate) fn new(entries: BTreeMap<Index, TextEntry>) -> Self {
+        Self {
+            entries,
+            kanji_readings: OnceLock::new(),
+            readings: OnceLock::new(),
+        }
+    }
+
+    /// Reads a dictionary in the given format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input cannot be read or is not a valid
+    /// dictionary in the given format.
+    pub fn from_reader(reader: impl BufRead, format: DictionaryFormat) -> Result<Self, LoadError> {
+        match format {
+            DictionaryFormat::Source => Ok(build(reader)?),
+            #[cfg(feature = "bincode")]
+            DictionaryFormat::Binary => {
+                use bincode::Options;
+
+                let mut reader = reader;
+                let mut bytes = vec![];
+                reader.read_to_end(&mut bytes)?;
+                // The limit guards against huge allocations for corrupt input.
+                Ok(bincode::DefaultOptions::new()
+                    .with_fixint_encoding()
+                    .allow_trailing_bytes()
+                    .with_limit(bytes.len() as u64)
+                    .deserialize(&bytes)?)
+            }
+            #[cfg(not(feature = "bincode"))]
+            DictionaryFormat::Binary => Err(LoadError::BinaryUnsupported),
+            DictionaryFormat::Compact => {
+                let mut reader = reader;
+                let mut bytes = vec![];
+                reader.read_to_end(&mut bytes)?;
+                let compact = CompactDictionary::from_bytes(bytes)?;
+                compact.validate()?;
+                Ok(compact.to_dictionary())
+            }
+        }
+    }
+
+    /// Reads a dictionary from a file. The format is inferred from the file
+    /// extension (see [`DictionaryFormat::from_path`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not a valid
+    /// dictionary.
+    pub fn load_from_path(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        Self::from_reader(BufReader::new(file), DictionaryFormat::from_path(path))
+    }
+
+    /// Writes the dictionary in the binary format, e.g. to be loaded later
+    /// with [`Dictionary::load_from_path`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dictionary cannot be written.
+    #[cfg(feature = "bincode")]
+    pub fn write_binary(&self, writer: impl Write) -> Result<(), LoadError> {
+        Ok(bincode::serialize_into(writer, self)?)
+    }
+
+    /// Writes the dictionary in the compact format, to be queried in place
+    /// with a [`CompactDictionary`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dictionary cannot be written.
+    pub fn write_compact(&self, writer: impl Write) -> std::io::Result<()> {
+        compact::write(self.entries.values(), self.kanji_readings_map(), writer)
+    }
+
     /// Returns an iterator over all entries exactly matching a given word in the dictionary.
     pub fn lookup_word<'s: 'w, 'w>(
         &'s self,
         word: &'w str,
     ) -> impl 'w + Iterator<Item = &'s TextEntry> {
-        self.0
+        self.entries
             .range(Index::from(word)..)
             .map_while(move |(Index { text, .. }, entry)| (text == word).then_some(entry))
     }
 
     /// Returns an iterator over all dictionary entries matching a given prefix.
     pub fn lookup_prefixed<'s>(&'s self, prefix: &'s str) -> impl Iterator<Item = &'s TextEntry> {
-        self.0
+        self.entries
             .range(Index::from(prefix)..)
             .map_while(move |(Index { text, .. }, entry)| text.starts_with(prefix).then_some(entry))
     }
+
+    /// Returns an iterator over all entries with a given reading, common
+    /// entries first. Readings are compared in hiragana, so the reading may
+    /// be given in hiragana or katakana.
+    pub fn lookup_reading<'s>(&'s self, reading: &str) -> impl Iterator<Item = &'s TextEntry> {
+        self.readings_map()
+            .get(&normalize_reading(reading))
+            .into_iter()
+            .flatten()
+            .filter_map(|index| self.entries.get(index))
+    }
+
+    /// Returns an iterator over all entries whose reading starts with a
+    /// given prefix, by reading and then common entries first. Readings are
+    /// compared in hiragana.
+    pub fn lookup_reading_prefixed<'s>(
+        &'s self,
+        prefix: &str,
+    ) -> impl Iterator<Item = &'s TextEntry> {
+        let prefix = normalize_reading(prefix);
+        self.readings_map()
+            .range(prefix.clone()..)
+            .take_while(move |(reading, _)| reading.starts_with(&prefix))
+            .flat_map(|(_, indices)| indices)
+            .filter_map(|index| self.entries.get(index))
+    }
+
+    /// Returns the entries by normalized reading.
+    fn readings_map(&self) -> &BTreeMap<String, Vec<Index>> {
+        self.readings.get_or_init(|| {
+            group_by_reading(self.entries.iter().map(|(index, entry)| {
+                let common = entry.text_is_common || entry.reading_is_common;
+                (index.clone(), entry.reading.as_str(), common)
+            }))
+        })
+    }
+
+    /// Returns the known readings of a single kanji, most frequent first.
+    ///
+    /// The readings are collected from the spans of all entries that cover
+    /// exactly one kanji.
+    pub fn kanji_readings(&self, kanji: char) -> &[String] {
+        self.kanji_readings_map()
+            .get(&kanji)
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the known readings of all kanji, most frequent first.
+    fn kanji_readings_map(&self) -> &HashMap<char, Vec<String>> {
+        self.kanji_readings
+            .get_or_init(|| kanji_readings_of(self.entries.values()))
+    }
+
+    /// Returns an iterator over all entries, sorted by text and then reading.
+    pub fn entries(&self) -> impl Iterator<Item = &TextEntry> {
+        self.entries.values()
+    }
+
+    /// Returns the number of entries in the dictionary.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the dictionary has no entries.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Counts the entries, words, common entries, names and kanji with known
+    /// readings of the dictionary.
+    pub fn statistics(&self) -> Statistics {
+        let mut statistics = Statistics {
+            entries: self.entries.len(),
+            kanji: self.kanji_readings_map().len(),
+            ..Statistics::default()
+        };
+        let mut previous = None;
+        for entry in self.entries.values() {
+            if previous != Some(&entry.text) {
+                statistics.words += 1;
+            }
+            previous = Some(&entry.text);
+            statistics.common += usize::from(entry.text_is_common || entry.reading_is_common);
+            statistics.names += usize::from(!entry.name_types.is_empty());
+        }
+        statistics
+    }
+
+    /// Adds the entries of another dictionary, replacing entries with the
+    /// same text and reading.
+    pub fn merge(&mut self, other: Dictionary) {
+        self.entries.extend(other.entries);
+        self.kanji_readings = OnceLock::new();
+        self.readings = OnceLock::new();
+    }
+
+    /// Replaces the frequency flags of all entries with those from a
+    /// `JMdict` XML file. Entries missing from it are not common.
+    pub fn add_frequencies(&mut self, jmdict: &str) {
+        self.readings = OnceLock::new();
+        for entry in self.entries.values_mut() {
+            entry.text_is_common = false;
+            entry.reading_is_common = false;
+        }
+
+        for pair in parse::jmdict_pairs(jmdict) {
+            let index = Index {
+                text: pair.text,
+                reading: pair.reading,
+            };
+            if let Some(entry) = self.entries.get_mut(&index) {
+                entry.text_is_common = pair.text_common;
+                entry.reading_is_common = pair.reading_common;
+            }
+        }
+    }
+
+    /// Adds proper nouns from a `JMnedict` XML file.
+    ///
+    /// `JMnedict` has no furigana, so the reading spans of each name are
+    /// guessed from the known readings of its kanji. Names that are already
+    /// in the dictionary with the same reading are only tagged with their
+    /// types.
+    pub fn add_names(&mut self, jmnedict: &str) {
+        let mut tagged = vec![];
+        let mut added = vec![];
+
+        for entry in parse::jmnedict_entries(jmnedict) {
+            let mut name_types = vec![];
+            for name_type in entry.name_types.into_iter().map(NameType::from_jmnedict) {
+                if !name_types.contains(&name_type) {
+                    name_types.push(name_type);
+                }
+            }
+
+            for (text, reading) in entry.pairs {
+                let index = Index {
+                    text: text.clone(),
+                    reading: reading.clone(),
+                };
+
+                if self.entries.contains_key(&index) {
+                    tagged.push((index, name_types.clone()));
+                    continue;
+                }
+
+                let Some(reading_spans) =
+                    align::guess(&text, &reading, |kanji| self.kanji_readings(kanji))
+                        .filter(|spans| !spans.is_empty())
+                else {
+                    continue;
+                };
+
+                added.push((
+                    index,
+                    TextEntry {
+                        text,
+                        text_is_common: false,
+                        reading,
+                        reading_is_common: false,
+                        reading_spans,
+                        low_confidence: true,
+                        name_types: name_types.clone(),
+                    },
+                ));
+            }
+        }
+
+        for (index, name_types) in tagged {
+            if let Some(entry) = self.entries.get_mut(&index) {
+                for name_type in name_types {
+                    if !entry.name_types.contains(&name_type) {
+                        entry.name_types.push(name_type);
+                    }
+                }
+            }
+        }
+
+        self.entries.extend(added);
+        self.kanji_readings = OnceLock::new();
+        self.readings = OnceLock::new();
+    }
+}
+
+/// Collects the known readings of single kanji from the spans of entries that
+/// cover exactly one kanji, most frequent first.
+fn kanji_readings_of<'e>(
+    entries: impl IntoIterator<Item = &'e TextEntry>,
+) -> HashMap<char, Vec<String>> {
+    let mut counts: HashMap<char, HashMap<&str, usize>> = HashMap::new();
+    for entry in entries {
+        let chars = entry.text.chars().collect::<Vec<_>>();
+        for span in &entry.reading_spans {
+            if span.start_index != span.end_index {
+                continue;
+            }
+            if let Some(&c) = chars
+                .get(span.start_index as usize)
+                .filter(|c| is_kanji(**c))
+            {
+                *counts.entry(c).or_default().entry(&span.text).or_default() += 1;
+            }
+        }
+    }
+
+    counts
+        .into_iter()
+        .map(|(c, readings)| {
+            let mut readings = readings.into_iter().collect::<Vec<_>>();
+            readings.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+            (
+                c,
+                readings.into_iter().map(|(r, _)| r.to_string()).collect(),
+            )
+        })
+        .collect()
+}
+
+/// A source of dictionary entries. Sources are [`Sync`], so that annotators
+/// can be shared across threads.
+pub trait Lookup: Sync {
+    /// Returns an iterator over all entries exactly matching a given word.
+    fn lookup_word<'s: 'w, 'w>(
+        &'s self,
+        word: &'w str,
+    ) -> Box<dyn 'w + Iterator<Item = &'s TextEntry>>;
+
+    /// Returns an iterator over all entries matching a given prefix.
+    fn lookup_prefixed<'s>(
+        &'s self,
+        prefix: &'s str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>>;
+
+    /// Returns an iterator over all entries with a given reading, common
+    /// entries first. Readings are compared in hiragana, so the reading may
+    /// be given in hiragana or katakana.
+    fn lookup_reading<'s>(&'s self, reading: &str) -> Box<dyn 's + Iterator<Item = &'s TextEntry>>;
+
+    /// Returns an iterator over all entries whose reading starts with a
+    /// given prefix, by reading and then common entries first. Readings are
+    /// compared in hiragana.
+    fn lookup_reading_prefixed<'s>(
+        &'s self,
+        prefix: &str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>>;
+
+    /// Returns the known readings of a single kanji, most frequent first.
+    fn kanji_readings(&self, kanji: char) -> &[String];
+}
+
+impl Lookup for Dictionary {
+    fn lookup_word<'s: 'w, 'w>(
+        &'s self,
+        word: &'w str,
+    ) -> Box<dyn 'w + Iterator<Item = &'s TextEntry>> {
+        Box::new(Dictionary::lookup_word(self, word))
+    }
+
+    fn lookup_prefixed<'s>(
+        &'s self,
+        prefix: &'s str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        Box::new(Dictionary::lookup_prefixed(self, prefix))
+    }
+
+    fn lookup_reading<'s>(&'s self, reading: &str) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        Box::new(Dictionary::lookup_reading(self, reading))
+    }
+
+    fn lookup_reading_prefixed<'s>(
+        &'s self,
+        prefix: &str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        Box::new(Dictionary::lookup_reading_prefixed(self, prefix))
+    }
+
+    fn kanji_readings(&self, kanji: char) -> &[String] {
+        Dictionary::kanji_readings(self, kanji)
+    }
+}
+
+/// A user dictionary layered on top of other dictionaries.
+#[derive(Debug, Clone)]
+struct Layer {
+    dictionary: Dictionary,
+    /// Suppressed entries of lower layers, by text and optionally reading.
+    suppressed: Vec<(String, Option<String>)>,
+}
+
+impl Layer {
+    fn suppresses(&self, entry: &TextEntry) -> bool {
+        self.suppressed.iter().any(|(text, reading)| {
+            *text == entry.text && reading.iter().all(|r| *r == entry.reading)
+        })
+    }
+}
+
+/// User dictionaries layered on top of a base dictionary.
+///
+/// If a layer has entries for a word, they replace the entries of all lower
+/// layers (and the base dictionary) for that word. Layers can also suppress
+/// entries of lower layers without replacing them.
+#[derive(Clone)]
+pub struct LayeredDictionary<'a> {
+    base: &'a dyn Lookup,
+    /// Layers in ascending order of priority.
+    layers: Vec<Layer>,
+    /// Known readings of the kanji with readings in any layer, merged in
+    /// order of priority on first use.
+    kanji_readings: OnceLock<HashMap<char, Vec<String>>>,
+}
+
+impl<'a> LayeredDictionary<'a> {
+    /// Creates a layered dictionary without any layers.
+    pub fn new(base: &'a dyn Lookup) -> Self {
+        Self {
+            base,
+            layers: vec![],
+            kanji_readings: OnceLock::new(),
+        }
+    }
+
+    /// Adds a layer with priority over all existing layers.
+    ///
+    /// The layer uses the same `text|reading|spans` line format as the
+    /// furigana dictionary, with some additions for hand-written files:
+    ///
+    /// - Empty lines and lines starting with `#` are ignored.
+    /// - `-text` suppresses all entries for `text` in lower layers.
+    /// - `-text|reading` suppresses only the entry with that reading.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input reader fails to read or parse.
+    pub fn add_layer(&mut self, input_reader: impl BufRead) -> Result<(), BuildError> {
+        let mut entries = String::new();
+        // The line numbers in the input of the lines in `entries`.
+        let mut entry_lines = vec![];
+        let mut suppressed = vec![];
+
+        for (i, line) in input_reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(suppression) = line.strip_prefix('-') {
+                let (text, reading) = match suppression.split_once('|') {
+                    Some((text, reading)) => (text, Some(reading.trim_end_matches('|'))),
+                    None => (suppression, None),
+                };
+                if text.is_empty() {
+                    return Err(BuildError::Parse {
+                        line: i + 1,
+                        content: line.to_string(),
+                    });
+                }
+                suppressed.push((text.to_string(), reading.map(ToString::to_string)));
+            } else {
+                entries.push_str(line);
+                entries.push('\n');
+                entry_lines.push(i + 1);
+            }
+        }
+
+        let dictionary = match build_validated(entries.as_bytes(), Validation::Strict) {
+            Ok(build) => build.dictionary,
+            Err(BuildError::Parse { line, content }) => {
+                return Err(BuildError::Parse {
+                    line: entry_lines[line - 1],
+                    content,
+                })
+            }
+            Err(BuildError::Malformed { line, issue }) => {
+                return Err(BuildError::Malformed {
+                    line: entry_lines[line - 1],
+                    issue,
+                })
+            }
+            Err(e) => return Err(e),
+        };
+        self.layers.push(Layer {
+            dictionary,
+            suppressed,
+        });
+        self.kanji_readings = OnceLock::new();
+
+        Ok(())
+    }
+
+    /// Writes the entries of all layers as a lindera user dictionary in the
+    /// CSV format of the given kind of tokenizer dictionary, so that the
+    /// tokenizer keeps their words together (see
+    /// [`AnnotatorBuilder::user_dictionary`] and
+    /// [`TokenizerDictionary::user_dictionary_format`]). Entries suppressed
+    /// by a higher layer are left out.
+    ///
+    /// [`AnnotatorBuilder::user_dictionary`]: crate::annotate::AnnotatorBuilder::user_dictionary
+    /// [`TokenizerDictionary::user_dictionary_format`]: crate::annotate::TokenizerDictionary::user_dictionary_format
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to the output fails.
+    pub fn write_tokenizer_dictionary(
+        &self,
+        mut output: impl Write,
+        format: TokenizerFormat,
+    ) -> std::io::Result<()> {
+        let row = match format {
+            TokenizerFormat::UniDic => unidic_row,
+            TokenizerFormat::Ipadic => ipadic_row,
+        };
+        for (level, layer) in (1..).zip(&self.layers) {
+            for entry in layer.dictionary.entries.values() {
+                if !self.is_suppressed(entry, level) {
+                    writeln!(output, "{}", row(entry))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the entry of the layer at `level` (the base dictionary being
+    /// level 0) is suppressed by a higher layer.
+    fn is_suppressed(&self, entry: &TextEntry, level: usize) -> bool {
+        self.layers[level..]
+            .iter()
+            .any(|layer| layer.suppresses(entry))
+    }
+
+    /// Whether the entry of the layer at `level` is found by
+    /// [`Lookup::lookup_word`]: it is not suppressed, and no higher layer
+    /// has entries for its text that are not suppressed.
+    fn is_visible(&self, entry: &TextEntry, level: usize) -> bool {
+        !self.is_suppressed(entry, level)
+            && (level + 1..=self.layers.len()).all(|higher| {
+                self.layers[higher - 1]
+                    .dictionary
+                    .lookup_word(&entry.text)
+                    .all(|shadowing| self.is_suppressed(shadowing, higher))
+            })
+    }
+
+    /// Returns the known readings of the kanji with readings in any layer,
+    /// or in an entry of the base dictionary that a layer suppresses: those
+    /// of the entries of each layer that are not suppressed by a higher
+    /// layer, highest layer first, followed by those of the base dictionary.
+    fn kanji_readings_map(&self) -> &HashMap<char, Vec<String>> {
+        self.kanji_readings.get_or_init(|| {
+            let mut merged: HashMap<char, Vec<String>> = HashMap::new();
+            for (i, layer) in self.layers.iter().enumerate().rev() {
+                let level = i + 1;
+                let visible = layer
+                    .dictionary
+                    .entries
+                    .values()
+                    .filter(|entry| !self.is_suppressed(entry, level));
+                for (kanji, readings) in kanji_readings_of(visible) {
+                    let merged = merged.entry(kanji).or_default();
+                    for reading in readings {
+                        if !merged.contains(&reading) {
+                            merged.push(reading);
+                        }
+                    }
+                }
+            }
+
+            // The readings of kanji in suppressed entries of the base are
+            // counted again without those entries, which takes a pass over
+            // all entries of the base (found by the empty prefix).
+            let suppressed = self
+                .layers
+                .iter()
+                .flat_map(|layer| &layer.suppressed)
+                .flat_map(|(text, _)| self.base.lookup_word(text))
+                .filter(|entry| self.is_suppressed(entry, 0));
+            let affected = kanji_readings_of(suppressed);
+            let base = if affected.is_empty() {
+                HashMap::new()
+            } else {
+                kanji_readings_of(self.base.lookup_prefixed("").filter(|entry| {
+                    !self.is_suppressed(entry, 0)
+                        && entry.text.chars().any(|c| affected.contains_key(&c))
+                }))
+            };
+            for &kanji in affected.keys() {
+                merged.entry(kanji).or_default();
+            }
+
+            for (kanji, readings) in &mut merged {
+                let base_readings = if affected.contains_key(kanji) {
+                    base.get(kanji).map_or(&[][..], Vec::as_slice)
+                } else {
+                    self.base.kanji_readings(*kanji)
+                };
+                for reading in base_readings {
+                    if !readings.contains(reading) {
+                        readings.push(reading.clone());
+                    }
+                }
+            }
+            merged
+        })
+    }
+
+    /// Returns the lookups of all layers, highest first, with their level.
+    fn levels(&self) -> impl Iterator<Item = (usize, &dyn Lookup)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, layer)| (i + 1, &layer.dictionary as &dyn Lookup))
+            .chain(std::iter::once((0, self.base)))
+    }
+}
+
+/// Quotes a field of a CSV row if necessary.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Normalizes a reading for lookups by reading, converting it to hiragana.
+/// Converting to katakana first makes long vowel marks (`ー`) in hiragana
+/// and katakana normalize alike.
+pub(crate) fn normalize_reading(reading: &str) -> String {
+    reading.to_katakana().to_hiragana()
+}
+
+/// Formats reading spans in the source format, e.g. `0:かん;1:じ`.
+fn source_spans(spans: &[ReadingSpan]) -> String {
+    spans
+        .iter()
+        .map(|span| {
+            if span.start_index == span.end_index {
+                format!("{}:{}", span.start_index, span.text)
+            } else {
+                format!("{}-{}:{}", span.start_index, span.end_index, span.text)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Formats an entry as a line of the source format. Fails if the entry
+/// contains separators, since they cannot be escaped.
+fn source_line(entry: &TextEntry) -> std::io::Result<String> {
+    let has_separators = |s: &str| s.contains(['|', '\n']);
+    if has_separators(&entry.text)
+        || has_separators(&entry.reading)
+        || entry.reading_spans.iter().any(|span| {
+            span.text.is_empty() || span.text.contains(';') || has_separators(&span.text)
+        })
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "cannot write {}|{} in the source format",
+                entry.text, entry.reading
+            ),
+        ));
+    }
+    Ok(format!(
+        "{}|{}|{}",
+        entry.text,
+        entry.reading,
+        source_spans(&entry.reading_spans)
+    ))
+}
+
+/// Formats an entry as a CSV row.
+fn csv_row(entry: &TextEntry) -> String {
+    let name_types = entry
+        .name_types
+        .iter()
+        .map(|name_type| name_type.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    format!(
+        "{},{},{},{},{},{},{name_types}",
+        csv_field(&entry.text),
+        csv_field(&entry.reading),
+        entry.text_is_common,
+        entry.reading_is_common,
+        csv_field(&source_spans(&entry.reading_spans)),
+        entry.low_confidence,
+    )
+}
+
+/// Writes entries, e.g. from [`Dictionary::entries`], in the given format.
+///
+/// # Errors
+///
+/// Returns an error if the entries cannot be written, or if an entry cannot
+/// be represented in the source format.
+pub fn export<'e>(
+    entries: impl IntoIterator<Item = &'e TextEntry>,
+    mut writer: impl Write,
+    format: ExportFormat,
+) -> std::io::Result<()> {
+    if format == ExportFormat::Csv {
+        writeln!(
+            writer,
+            "text,reading,text_is_common,reading_is_common,reading_spans,low_confidence,name_types"
+        )?;
+    }
+    for entry in entries {
+        match format {
+            #[cfg(feature = "serde")]
+            ExportFormat::JsonLines => serde_json::to_writer(&mut writer, entry)?,
+            ExportFormat::Csv => write!(writer, "{}", csv_row(entry))?,
+            ExportFormat::Source => write!(writer, "{}", source_line(entry)?)?,
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Formats an entry as a row of a lindera user dictionary in the `UniDic` CSV
+/// format, with the text as its lemma and the reading as its pronunciation.
+fn unidic_row(entry: &TextEntry) -> String {
+    let text = csv_field(&entry.text);
+    let reading = entry.reading.to_katakana();
+    let reading = csv_field(&reading);
+    format!(
+        "{text},0,0,-10000,名詞,普通名詞,一般,*,*,*,{reading},{text},{text},{reading},{text},{reading},*,*,*,*,*"
+    )
+}
+
+/// Formats an entry as a row of a lindera user dictionary in the IPADIC CSV
+/// format, with the text as its base form and the reading as its reading and
+/// pronunciation.
+fn ipadic_row(entry: &TextEntry) -> String {
+    let text = csv_field(&entry.text);
+    let reading = entry.reading.to_katakana();
+    let reading = csv_field(&reading);
+    format!("{text},0,0,-10000,名詞,一般,*,*,*,*,{text},{reading},{reading}")
+}
+
+impl Lookup for LayeredDictionary<'_> {
+    fn lookup_word<'s: 'w, 'w>(
+        &'s self,
+        word: &'w str,
+    ) -> Box<dyn 'w + Iterator<Item = &'s TextEntry>> {
+        for (level, lookup) in self.levels() {
+            let entries = lookup
+                .lookup_word(word)
+                .filter(|entry| !self.is_suppressed(entry, level))
+                .collect::<Vec<_>>();
+            if !entries.is_empty() {
+                return Box::new(entries.into_iter());
+            }
+        }
+        Box::new(std::iter::empty())
+    }
+
+    fn lookup_prefixed<'s>(
+        &'s self,
+        prefix: &'s str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        // Entries come sorted by text from every level, so they are merged
+        // text by text, taking the entries of the highest level that has any
+        // for the text that are not suppressed. Entries of lower levels for
+        // the same text are shadowed.
+        let mut levels = self
+            .levels()
+            .map(|(level, lookup)| (level, lookup.lookup_prefixed(prefix).peekable()))
+            .collect::<Vec<_>>();
+
+        Box::new(
+            std::iter::from_fn(move || {
+                let text = levels
+                    .iter_mut()
+                    .filter_map(|(_, entries)| entries.peek().map(|&entry| entry.text.as_str()))
+                    .min()?;
+
+                let mut visible = vec![];
+                for (level, entries) in &mut levels {
+                    let level = *level;
+                    let entries =
+                        std::iter::from_fn(|| entries.next_if(|entry| entry.text == text));
+                    if visible.is_empty() {
+                        visible.extend(entries.filter(|entry| !self.is_suppressed(entry, level)));
+                    } else {
+                        entries.for_each(drop);
+                    }
+                }
+                Some(visible)
+            })
+            .flatten(),
+        )
+    }
+
+    fn lookup_reading<'s>(&'s self, reading: &str) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        let mut entries = self
+            .levels()
+            .flat_map(|(level, lookup)| {
+                lookup
+                    .lookup_reading(reading)
+                    .filter(move |entry| self.is_visible(entry, level))
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| !(entry.text_is_common || entry.reading_is_common));
+        Box::new(entries.into_iter())
+    }
+
+    fn lookup_reading_prefixed<'s>(
+        &'s self,
+        prefix: &str,
+    ) -> Box<dyn 's + Iterator<Item = &'s TextEntry>> {
+        let mut entries = self
+            .levels()
+            .flat_map(|(level, lookup)| {
+                lookup
+                    .lookup_reading_prefixed(prefix)
+                    .filter(move |entry| self.is_visible(entry, level))
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_cached_key(|entry| {
+            let common = entry.text_is_common || entry.reading_is_common;
+            (normalize_reading(&entry.reading), !common)
+        });
+        Box::new(entries.into_iter())
+    }
+
+    fn kanji_readings(&self, kanji: char) -> &[String] {
+        self.kanji_readings_map()
+            .get(&kanji)
+            .map_or_else(|| self.base.kanji_readings(kanji), Vec::as_slice)
+    }
+}
+
+/// Counts of what a dictionary contains, from [`Dictionary::statistics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// The number of entries.
+    pub entries: usize,
+    /// The number of distinct texts.
+    pub words: usize,
+    /// The number of entries whose text or reading is common, which
+    /// [`UncommonOnly`](crate::select::heuristic::UncommonOnly) skips.
+    pub common: usize,
+    /// The number of entries that are proper nouns.
+    pub names: usize,
+    /// The number of kanji with known readings.
+    pub kanji: usize,
+}
+
+/// Formats of lindera user dictionaries written by
+/// [`LayeredDictionary::write_tokenizer_dictionary`]. The format must match
+/// the kind of the tokenizer dictionary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TokenizerFormat {
+    /// The format of `UniDic`, e.g. the dictionary embedded in lindera.
+    #[default]
+    UniDic,
+    /// The format of IPADIC.
+    Ipadic,
+}
+
+/// Formats a dictionary can be loaded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DictionaryFormat {
+    /// The `JmdictFurigana.txt` text format, e.g. `漢字|かんじ|0:かん;1:じ`.
+    Source,
+    /// The binary format written by [`Dictionary::write_binary`]. Requires
+    /// the `bincode` feature.
+    Binary,
+    /// The compact format written by [`Dictionary::write_compact`] and
+    /// embedded by the `integrated` feature. To query it in place instead of
+    /// loading it fully, use a [`CompactDictionary`].
+    Compact,
+}
+
+impl DictionaryFormat {
+    /// Infers the format from a file extension: `.bin` files are
+    /// [`DictionaryFormat::Binary`], `.ardc` files are
+    /// [`DictionaryFormat::Compact`], all others [`DictionaryFormat::Source`].
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("bin") => Self::Binary,
+            Some(extension) if extension.eq_ignore_ascii_case("ardc") => Self::Compact,
+            _ => Self::Source,
+        }
+    }
+}
+
+/// Formats a dictionary can be exported to with [`export`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line, with the fields of [`TextEntry`]. Requires
+    /// the `serde` feature.
+    #[cfg(feature = "serde")]
+    JsonLines,
+    /// CSV with a header row. Reading spans are in the source format, and
+    /// name types are separated by `;`.
+    Csv,
+    /// The `text|reading|spans` format of `JmdictFurigana.txt`, which can be
+    /// built into a dictionary again. Name types are left out, and guessed
+    /// readings are no longer marked as low confidence.
+    Source,
+}
+
+/// Error type for dictionary loading.
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    /// Error reading the input.
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Error building a dictionary from the source format.
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    /// Error decoding or encoding the binary format.
+    #[cfg(feature = "bincode")]
+    #[error("Invalid binary dictionary: {0}")]
+    Binary(#[from] bincode::Error),
+    /// The binary format is not supported without the `bincode` feature.
+    #[cfg(not(feature = "bincode"))]
+    #[error("Binary dictionaries require the `bincode` feature")]
+    BinaryUnsupported,
+    /// Error decoding the compact format.
+    #[error("Invalid compact dictionary: {0}")]
+    Compact(&'static str),
 }
 
 /// Error type for dictionary building.
@@ -120,40 +1136,218 @@ pub enum BuildError {
     /// Error reading a line.
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
-    /// Error parsing a line.
-    #[error("Failed to parse line: {0}")]
-    Parse(String),
+    /// A line that cannot be parsed.
+    #[error("Failed to parse line {line}: {content}")]
+    Parse {
+        /// The line number, starting from 1.
+        line: usize,
+        /// The content of the line.
+        content: String,
+    },
+    /// A line with malformed reading spans, with [`Validation::Strict`].
+    #[error("Malformed reading spans on line {line}: {issue}")]
+    Malformed {
+        /// The line number, starting from 1.
+        line: usize,
+        /// What is wrong with the line.
+        issue: SpanIssue,
+    },
+}
+
+/// How [`build_validated`] treats malformed reading spans.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Validation {
+    /// Fail on the first line that cannot be parsed or has malformed
+    /// reading spans.
+    Strict,
+    /// Sort the reading spans of each line, drop spans that are out of
+    /// bounds or overlap a preceding span, and report the lines repaired.
+    /// Fail on the first line that cannot be parsed.
+    #[default]
+    Lenient,
+    /// Repair reading spans like [`Validation::Lenient`], and skip and
+    /// report lines that cannot be parsed instead of failing, e.g. to list
+    /// every problem of a file at once.
+    SkipUnparsed,
+}
+
+/// A problem with the reading spans of a dictionary line.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SpanIssue {
+    /// The span ends before it starts, or after the end of the text.
+    #[error("span {start}-{end} is out of bounds of the {len} characters of the text")]
+    OutOfBounds {
+        /// The start index of the span.
+        start: u8,
+        /// The end index of the span.
+        end: u8,
+        /// The number of characters of the text.
+        len: usize,
+    },
+    /// The spans are not sorted by start index.
+    #[error("spans are not sorted")]
+    Unsorted,
+    /// The span overlaps a preceding span.
+    #[error("span {start}-{end} overlaps a preceding span")]
+    Overlapping {
+        /// The start index of the span.
+        start: u8,
+        /// The end index of the span.
+        end: u8,
+    },
+}
+
+/// A dictionary line with malformed reading spans.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedLine {
+    /// The line number, starting from 1.
+    pub line: usize,
+    /// What is wrong with the line.
+    pub issue: SpanIssue,
+}
+
+/// A dictionary line that cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnparsedLine {
+    /// The line number, starting from 1.
+    pub line: usize,
+    /// The content of the line.
+    pub content: String,
+}
+
+/// A dictionary built by [`build_validated`], with the lines repaired or
+/// skipped.
+#[derive(Debug)]
+pub struct ValidatedBuild {
+    /// The dictionary.
+    pub dictionary: Dictionary,
+    /// The lines whose reading spans were repaired, in order.
+    pub malformed: Vec<MalformedLine>,
+    /// The lines that could not be parsed and were skipped, in order. Only
+    /// lines are skipped with [`Validation::SkipUnparsed`].
+    pub unparsed: Vec<UnparsedLine>,
+}
+
+/// Sorts the spans, dropping spans that are out of bounds of the text or
+/// overlap a preceding span. Returns the issues found.
+fn repair_spans(text: &str, spans: &mut Vec<ReadingSpan>) -> Vec<SpanIssue> {
+    let len = text.chars().count();
+    let mut issues = vec![];
+
+    spans.retain(|span| {
+        let in_bounds = span.start_index <= span.end_index && (span.end_index as usize) < len;
+        if !in_bounds {
+            issues.push(SpanIssue::OutOfBounds {
+                start: span.start_index,
+                end: span.end_index,
+                len,
+            });
+        }
+        in_bounds
+    });
+
+    if spans
+        .windows(2)
+        .any(|pair| pair[0].start_index > pair[1].start_index)
+    {
+        issues.push(SpanIssue::Unsorted);
+        spans.sort_by_key(|span| span.start_index);
+    }
+
+    let mut next_index = 0;
+    spans.retain(|span| {
+        let overlaps = (span.start_index as usize) < next_index;
+        if overlaps {
+            issues.push(SpanIssue::Overlapping {
+                start: span.start_index,
+                end: span.end_index,
+            });
+        } else {
+            next_index = span.end_index as usize + 1;
+        }
+        !overlaps
+    });
+
+    issues
 }
 
-/// Builds a dictionary from a reader.
+/// Builds a dictionary from a reader, repairing malformed reading spans.
 ///
 /// # Errors
 ///
-/// Returns an error if the input reader fails to read or parse.
+/// Returns an error if the input reader fails to read, or on the first line
+/// that cannot be parsed.
 pub fn build(input_reader: impl BufRead) -> Result<Dictionary, BuildError> {
-    let mut tree = input_reader
-        .lines()
-        .try_fold(BTreeMap::default(), |mut map, line| {
-            let line = line?;
-            let (_, entry) =
-                dictionary_line(&line).map_err(|_| BuildError::Parse(line.to_string()))?;
+    build_validated(input_reader, Validation::Lenient).map(|build| build.dictionary)
+}
 
-            let index = Index {
+/// Builds a dictionary from a reader, validating the reading spans of each
+/// line: spans must be within the text, sorted and must not overlap.
+///
+/// # Errors
+///
+/// Returns an error if the input reader fails to read, on the first line
+/// that cannot be parsed unless with [`Validation::SkipUnparsed`], or, with
+/// [`Validation::Strict`], on the first line with malformed reading spans.
+pub fn build_validated(
+    input_reader: impl BufRead,
+    validation: Validation,
+) -> Result<ValidatedBuild, BuildError> {
+    let mut tree = BTreeMap::default();
+    let mut malformed = vec![];
+    let mut unparsed = vec![];
+
+    for (i, line) in input_reader.lines().enumerate() {
+        let line = line?;
+        let Ok((_, entry)) = dictionary_line(&line) else {
+            if validation != Validation::SkipUnparsed {
+                return Err(BuildError::Parse {
+                    line: i + 1,
+                    content: line,
+                });
+            }
+            unparsed.push(UnparsedLine {
+                line: i + 1,
+                content: line,
+            });
+            continue;
+        };
+
+        let mut reading_spans = entry
+            .reading_spans
+            .into_iter()
+            .map(Into::into)
+            .collect::<Vec<_>>();
+        let issues = repair_spans(entry.text, &mut reading_spans);
+        if let (Validation::Strict, Some(issue)) = (validation, issues.first()) {
+            return Err(BuildError::Malformed {
+                line: i + 1,
+                issue: issue.clone(),
+            });
+        }
+        malformed.extend(
+            issues
+                .into_iter()
+                .map(|issue| MalformedLine { line: i + 1, issue }),
+        );
+
+        let index = Index {
+            text: entry.text.to_string(),
+            reading: entry.reading.to_string(),
+        };
+        tree.insert(
+            index,
+            TextEntry {
                 text: entry.text.to_string(),
+                text_is_common: false,
                 reading: entry.reading.to_string(),
-            };
-            map.insert(
-                index,
-                TextEntry {
-                    text: entry.text.to_string(),
-                    text_is_common: false,
-                    reading: entry.reading.to_string(),
-                    reading_is_common: false,
-                    reading_spans: entry.reading_spans.into_iter().map(Into::into).collect(),
-                },
-            );
-            Ok::<_, BuildError>(map)
-        })?;
+                reading_is_common: false,
+                reading_spans,
+                low_confidence: false,
+                name_types: vec![],
+            },
+        );
+    }
 
     frequency_entries().for_each(|freq| {
         if let Some(e) = tree.get_mut(&Index {
@@ -165,5 +1359,127 @@ pub fn build(input_reader: impl BufRead) -> Result<Dictionary, BuildError> {
         }
     });
 
-    Ok(Dictionary(tree))
+    Ok(ValidatedBuild {
+        dictionary: Dictionary::new(tree),
+        malformed,
+        unparsed,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::{
+        build_validated, BuildError, LayeredDictionary, MalformedLine, ReadingSpan, SpanIssue,
+        TextEntry, UnparsedLine, Validation,
+    };
+
+    /// Returns an uncommon entry with reading spans given as
+    /// `(start_index, end_index, text)`.
+    pub(crate) fn entry(text: &str, reading: &str, spans: &[(u8, u8, &str)]) -> TextEntry {
+        TextEntry {
+            text: text.to_string(),
+            text_is_common: false,
+            reading: reading.to_string(),
+            reading_is_common: false,
+            reading_spans: spans
+                .iter()
+                .map(|&(start_index, end_index, text)| ReadingSpan {
+                    start_index,
+                    end_index,
+                    text: text.to_string(),
+                })
+                .collect(),
+            low_confidence: false,
+            name_types: vec![],
+        }
+    }
+
+    #[test]
+    fn validate_spans() {
+        let source =
+            "漢字|かんじ|1:じ;0:かん\n日本|にほん|0:に;1-3:ほん\n今日|きょう|0-1:きょう;1:ょう\n";
+
+        let build = build_validated(source.as_bytes(), Validation::Lenient).unwrap();
+        assert_eq!(
+            build.malformed,
+            [
+                MalformedLine {
+                    line: 1,
+                    issue: SpanIssue::Unsorted,
+                },
+                MalformedLine {
+                    line: 2,
+                    issue: SpanIssue::OutOfBounds {
+                        start: 1,
+                        end: 3,
+                        len: 2,
+                    },
+                },
+                MalformedLine {
+                    line: 3,
+                    issue: SpanIssue::Overlapping { start: 1, end: 1 },
+                },
+            ],
+        );
+
+        let spans = |word| {
+            build
+                .dictionary
+                .lookup_word(word)
+                .next()
+                .unwrap()
+                .reading_spans
+                .iter()
+                .map(|s| (s.start_index, s.end_index, s.text.as_str()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(spans("漢字"), [(0, 0, "かん"), (1, 1, "じ")]);
+        assert_eq!(spans("日本"), [(0, 0, "に")]);
+        assert_eq!(spans("今日"), [(0, 1, "きょう")]);
+
+        assert!(matches!(
+            build_validated(source.as_bytes(), Validation::Strict),
+            Err(BuildError::Malformed {
+                line: 1,
+                issue: SpanIssue::Unsorted,
+            }),
+        ));
+
+        let source = "漢字|かんじ|0:かん;1:じ\n漢字\n日本|にほん|0-1:にほん\n今日|\n";
+        let build = build_validated(source.as_bytes(), Validation::SkipUnparsed).unwrap();
+        assert_eq!(
+            build.unparsed,
+            [
+                UnparsedLine {
+                    line: 2,
+                    content: "漢字".to_string(),
+                },
+                UnparsedLine {
+                    line: 4,
+                    content: "今日|".to_string(),
+                },
+            ],
+        );
+        assert_eq!(build.dictionary.len(), 2);
+        assert!(matches!(
+            super::build(source.as_bytes()),
+            Err(BuildError::Parse { line: 2, .. }),
+        ));
+        assert!(matches!(
+            build_validated(source.as_bytes(), Validation::Lenient),
+            Err(BuildError::Parse { line: 2, .. }),
+        ));
+        assert!(matches!(
+            build_validated(source.as_bytes(), Validation::Strict),
+            Err(BuildError::Parse { line: 2, .. }),
+        ));
+
+        let mut layered = LayeredDictionary::new(&build.dictionary);
+        assert!(matches!(
+            layered.add_layer("# comment\n\n漢字|かんじ|1:じ;0:かん\n".as_bytes()),
+            Err(BuildError::Malformed { line: 3, .. }),
+        ));
+    }
 }
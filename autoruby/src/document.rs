@@ -0,0 +1,167 @@
+//! Document-aware annotation.
+//!
+//! A [`Document`] splits its input into [`Segment`]s, so that only prose is
+//! annotated and everything else (markup, code, metadata) is reproduced
+//! byte-for-byte.
+
+use std::{borrow::Cow, ops::Range};
+
+use crate::{
+    annotate::{AnnotateError, AnnotatedText, RenderMode},
+    format::Format,
+    select::Select,
+};
+
+mod html;
+mod latex;
+mod markdown;
+
+pub use html::Html;
+pub use latex::Latex;
+pub use markdown::Markdown;
+
+/// A contiguous piece of a document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segment<'a> {
+    /// Content that must be reproduced verbatim.
+    Verbatim(&'a str),
+    /// Prose that may be annotated.
+    Text(&'a str),
+}
+
+/// Splits documents into annotatable and verbatim segments.
+pub trait Document {
+    /// Splits the input into segments. Concatenating the segments in order
+    /// must reproduce the input exactly.
+    fn segments<'t>(&self, input: &'t str) -> Vec<Segment<'t>>;
+}
+
+/// Treats the entire input as prose.
+pub struct Plain;
+
+impl Document for Plain {
+    fn segments<'t>(&self, input: &'t str) -> Vec<Segment<'t>> {
+        vec![Segment::Text(input)]
+    }
+}
+
+/// Builds a list of segments from the byte ranges of the prose in `input`.
+///
+/// Ranges may be given in any order. Overlapping and adjacent ranges are
+/// merged, so that words split across several parser events are tokenized
+/// together.
+fn from_text_ranges(
+    input: &str,
+    ranges: impl IntoIterator<Item = Range<usize>>,
+) -> Vec<Segment<'_>> {
+    let mut ranges = ranges
+        .into_iter()
+        .filter(|r| !r.is_empty())
+        .collect::<Vec<_>>();
+    ranges.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<Range<usize>> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    let mut segments = vec![];
+    let mut position = 0;
+    for range in merged {
+        if position < range.start {
+            segments.push(Segment::Verbatim(&input[position..range.start]));
+        }
+        segments.push(Segment::Text(&input[range.clone()]));
+        position = range.end;
+    }
+    if position < input.len() {
+        segments.push(Segment::Verbatim(&input[position..]));
+    }
+
+    segments
+}
+
+/// An annotated segment of a document.
+#[derive(Clone, Debug)]
+pub enum AnnotatedSegment<'a> {
+    /// Content reproduced verbatim.
+    Verbatim(&'a str),
+    /// Annotated prose.
+    Text(AnnotatedText<'a>),
+}
+
+/// A complete document with annotations.
+#[derive(Clone, Debug, Default)]
+pub struct AnnotatedDocument<'a> {
+    /// The segments of the document.
+    pub segments: Vec<AnnotatedSegment<'a>>,
+}
+
+impl<'a> AnnotatedDocument<'a> {
+    /// Render the annotated document into a string.
+    ///
+    /// The same selector is used for every segment, so stateful selectors
+    /// (e.g. [`crate::select::filter::FirstOccurrence`]) apply to the
+    /// document as a whole.
+    pub fn render(&'a self, selector: &dyn Select<'a>, format: &dyn Format) -> String {
+        self.render_with_mode(selector, format, RenderMode::Mono)
+    }
+
+    /// Render the annotated document into a string, using the given mode.
+    pub fn render_with_mode(
+        &'a self,
+        selector: &dyn Select<'a>,
+        format: &dyn Format,
+        mode: RenderMode,
+    ) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                AnnotatedSegment::Verbatim(text) => Cow::Borrowed(*text),
+                AnnotatedSegment::Text(text) => {
+                    Cow::Owned(text.render_with_mode(selector, format, mode))
+                }
+            })
+            .collect()
+    }
+
+    /// Render the annotated document into a string.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry selected for a fragment has invalid
+    /// reading spans.
+    pub fn try_render(
+        &'a self,
+        selector: &dyn Select<'a>,
+        format: &dyn Format,
+    ) -> Result<String, AnnotateError> {
+        self.try_render_with_mode(selector, format, RenderMode::Mono)
+    }
+
+    /// Render the annotated document into a string, using the given mode.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry selected for a fragment has invalid
+    /// reading spans.
+    pub fn try_render_with_mode(
+        &'a self,
+        selector: &dyn Select<'a>,
+        format: &dyn Format,
+        mode: RenderMode,
+    ) -> Result<String, AnnotateError> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                AnnotatedSegment::Verbatim(text) => Ok(Cow::Borrowed(*text)),
+                AnnotatedSegment::Text(text) => text
+                    .try_render_with_mode(selector, format, mode)
+                    .map(Cow::Owned),
+            })
+            .collect()
+    }
+}
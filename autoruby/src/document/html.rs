@@ -0,0 +1,250 @@
+use super::{from_text_ranges, Document, Segment};
+
+/// Elements whose content is raw text (or escapable raw text) and can never
+/// contain annotations.
+const RAW_TEXT_ELEMENTS: [&str; 8] = [
+    "script", "style", "title", "textarea", "xmp", "iframe", "noembed", "noframes",
+];
+
+/// Elements that have no content and no end tag.
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+#[derive(Debug)]
+struct Tag<'a> {
+    name: String,
+    attributes: Vec<(String, &'a str)>,
+    is_end: bool,
+    is_self_closing: bool,
+    /// Byte length of the tag in the input.
+    len: usize,
+}
+
+/// HTML documents.
+///
+/// Only text nodes are annotated. Tags, attribute values, comments and the
+/// contents of raw text elements (`<script>`, `<style>`, `<title>`, etc.) are
+/// left untouched, as are the contents of:
+///
+/// - elements listed in [`Html::skip_elements`],
+/// - elements with a `translate="no"` attribute, and
+/// - elements with a `data-autoruby="off"` attribute.
+#[derive(Clone, Debug)]
+pub struct Html {
+    /// Names of elements whose content is never annotated.
+    pub skip_elements: Vec<String>,
+}
+
+impl Html {
+    /// Elements skipped by [`Html::default`].
+    pub const DEFAULT_SKIP_ELEMENTS: [&'static str; 11] = [
+        "code", "pre", "kbd", "samp", "ruby", "rb", "rt", "rp", "rtc", "math", "svg",
+    ];
+
+    /// Whether the contents of the element opened by the tag are skipped.
+    fn skips(&self, tag: &Tag) -> bool {
+        self.skip_elements
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(&tag.name))
+            || tag.attributes.iter().any(|(name, value)| {
+                (name == "translate" && value.eq_ignore_ascii_case("no"))
+                    || (name == "data-autoruby" && value.eq_ignore_ascii_case("off"))
+            })
+    }
+}
+
+impl Default for Html {
+    fn default() -> Self {
+        Self {
+            skip_elements: Self::DEFAULT_SKIP_ELEMENTS.map(String::from).to_vec(),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '/' | '>' | '=' | '"' | '\'' | '<')
+}
+
+/// Parses a start or end tag at the beginning of `input`.
+fn parse_tag(input: &str) -> Option<Tag<'_>> {
+    let mut rest = input.strip_prefix('<')?;
+    let is_end = if let Some(r) = rest.strip_prefix('/') {
+        rest = r;
+        true
+    } else {
+        false
+    };
+
+    if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let name_len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+    let name = rest[..name_len].to_ascii_lowercase();
+    rest = &rest[name_len..];
+
+    let mut attributes = vec![];
+    let mut is_self_closing = false;
+
+    loop {
+        rest = rest.trim_start();
+        if let Some(r) = rest.strip_prefix('>') {
+            rest = r;
+            break;
+        } else if let Some(r) = rest.strip_prefix('/') {
+            is_self_closing = r.starts_with('>');
+            rest = r;
+            continue;
+        } else if rest.is_empty() {
+            return None;
+        }
+
+        let attribute_len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len()).max(1);
+        let attribute = rest[..attribute_len].to_ascii_lowercase();
+        rest = rest[attribute_len..].trim_start();
+
+        let value = if let Some(r) = rest.strip_prefix('=') {
+            let r = r.trim_start();
+            let (value, r) = if let Some(quote @ ('"' | '\'')) = r.chars().next() {
+                let end = r[1..].find(quote)?;
+                (&r[1..=end], &r[end + 2..])
+            } else {
+                let end = r
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(r.len());
+                (&r[..end], &r[end..])
+            };
+            rest = r;
+            value
+        } else {
+            ""
+        };
+
+        attributes.push((attribute, value));
+    }
+
+    Some(Tag {
+        name,
+        attributes,
+        is_end,
+        is_self_closing,
+        len: input.len() - rest.len(),
+    })
+}
+
+/// Finds the byte offset of the end tag closing a raw text element.
+fn find_raw_text_end(input: &str, name: &str) -> usize {
+    let lowercase = input.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(i) = lowercase[offset..].find("</") {
+        let start = offset + i;
+        let after = &lowercase[start + 2..];
+        if after.starts_with(name)
+            && after[name.len()..].starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        {
+            return start;
+        }
+        offset = start + 2;
+    }
+    input.len()
+}
+
+impl Document for Html {
+    fn segments<'t>(&self, input: &'t str) -> Vec<Segment<'t>> {
+        // Open elements, and whether each one is skipped.
+        let mut stack: Vec<(String, bool)> = vec![];
+        let mut text_ranges = vec![];
+        let mut position = 0;
+
+        while position < input.len() {
+            let rest = &input[position..];
+
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                position += 4 + comment.find("-->").map_or(comment.len(), |i| i + 3);
+            } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+                position += 9 + cdata.find("]]>").map_or(cdata.len(), |i| i + 3);
+            } else if rest.starts_with("<!") || rest.starts_with("<?") {
+                position += rest.find('>').map_or(rest.len(), |i| i + 1);
+            } else if let Some(tag) = parse_tag(rest) {
+                position += tag.len;
+
+                if tag.is_end {
+                    if let Some(i) = stack.iter().rposition(|(name, _)| *name == tag.name) {
+                        stack.truncate(i);
+                    }
+                } else if RAW_TEXT_ELEMENTS.contains(&tag.name.as_str()) {
+                    if !tag.is_self_closing {
+                        position += find_raw_text_end(&input[position..], &tag.name);
+                    }
+                } else if !tag.is_self_closing && !VOID_ELEMENTS.contains(&tag.name.as_str()) {
+                    let skip = self.skips(&tag);
+                    stack.push((tag.name, skip));
+                }
+            } else {
+                // Text runs until the next `<`, which may or may not start markup.
+                let len = rest
+                    .char_indices()
+                    .skip(1)
+                    .find_map(|(i, c)| (c == '<').then_some(i))
+                    .unwrap_or(rest.len());
+                if !stack.iter().any(|(_, skip)| *skip) {
+                    text_ranges.push(position..position + len);
+                }
+                position += len;
+            }
+        }
+
+        from_text_ranges(input, text_ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::document;
+
+    #[test]
+    fn html_segments() {
+        use document::{Document, Segment};
+
+        let input = r#"<!DOCTYPE html>
+<html lang="ja">
+<head><title>漢字</title><style>p::before { content: "漢字"; }</style></head>
+<body>
+<p title="漢字">本文<img alt="画像" src="a.png">です。</p>
+<!-- 注釈 -->
+<p><ruby>漢<rt>かん</rt></ruby>字と<code>変数</code>と<span translate="no">固有</span>。</p>
+<div data-autoruby="off"><p>対象外</p></div>
+<script>const s = "</p>漢字";</script>
+<aside>余談</aside>
+</body>
+</html>
+"#;
+
+        let html = document::Html {
+            skip_elements: vec!["aside".to_string()],
+        };
+
+        let text = |segments: Vec<Segment<'static>>| {
+            segments
+                .into_iter()
+                .filter_map(|s| match s {
+                    Segment::Text(t) if !t.trim().is_empty() => Some(t),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            text(document::Html::default().segments(input)),
+            ["本文", "です。", "字と", "と", "。", "余談"],
+        );
+        assert_eq!(
+            text(html.segments(input)),
+            ["本文", "です。", "漢", "かん", "字と", "変数", "と", "。"],
+        );
+    }
+}
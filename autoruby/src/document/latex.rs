@@ -0,0 +1,343 @@
+use super::{from_text_ranges, Document, Segment};
+
+/// Environments whose content is not typeset as prose.
+const VERBATIM_ENVIRONMENTS: [&str; 23] = [
+    "verbatim",
+    "verbatim*",
+    "Verbatim",
+    "lstlisting",
+    "minted",
+    "comment",
+    "filecontents",
+    "filecontents*",
+    "math",
+    "displaymath",
+    "equation",
+    "equation*",
+    "align",
+    "align*",
+    "alignat",
+    "alignat*",
+    "gather",
+    "gather*",
+    "multline",
+    "multline*",
+    "flalign",
+    "flalign*",
+    "eqnarray",
+];
+
+/// LaTeX documents.
+///
+/// Only body text and the arguments of typesetting commands (e.g.
+/// `\section{..}`, `\textbf{..}`) are annotated. Comments, math, verbatim
+/// environments, `\verb`, optional arguments, environment names and the
+/// arguments of commands listed in [`Latex::skip_commands`] are left
+/// untouched.
+#[derive(Clone, Debug)]
+pub struct Latex {
+    /// Names of commands (without the leading backslash) whose arguments are
+    /// never annotated.
+    pub skip_commands: Vec<String>,
+}
+
+impl Latex {
+    /// Commands skipped by [`Latex::default`].
+    pub const DEFAULT_SKIP_COMMANDS: [&'static str; 30] = [
+        "documentclass",
+        "usepackage",
+        "RequirePackage",
+        "input",
+        "include",
+        "includeonly",
+        "includegraphics",
+        "bibliography",
+        "bibliographystyle",
+        "label",
+        "ref",
+        "eqref",
+        "pageref",
+        "autoref",
+        "nameref",
+        "cref",
+        "Cref",
+        "cite",
+        "citep",
+        "citet",
+        "nocite",
+        "url",
+        "href",
+        "hypersetup",
+        "newcommand",
+        "renewcommand",
+        "providecommand",
+        "newenvironment",
+        "renewenvironment",
+        "ruby",
+    ];
+}
+
+impl Default for Latex {
+    fn default() -> Self {
+        Self {
+            skip_commands: Self::DEFAULT_SKIP_COMMANDS.map(String::from).to_vec(),
+        }
+    }
+}
+
+/// Returns the byte length of the group starting at the beginning of `input`
+/// and delimited by `open` and `close`, taking nesting and escapes into
+/// account.
+fn group_len(input: &str, open: char, close: char) -> usize {
+    let mut depth = 0usize;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return i + c.len_utf8();
+                }
+            }
+            _ => {}
+        }
+    }
+    input.len()
+}
+
+/// Returns the byte length of the whitespace preceding an argument, which
+/// may not contain a paragraph break.
+fn argument_space_len(input: &str) -> usize {
+    let len = input
+        .find(|c: char| !c.is_whitespace())
+        .unwrap_or(input.len());
+    if input[..len].matches('\n').count() > 1 {
+        0
+    } else {
+        len
+    }
+}
+
+/// Returns the byte length of all optional and mandatory arguments at the
+/// beginning of `input`.
+fn arguments_len(input: &str) -> usize {
+    let mut position = 0;
+    loop {
+        let space = argument_space_len(&input[position..]);
+        let rest = &input[position + space..];
+        if rest.starts_with('{') {
+            position += space + group_len(rest, '{', '}');
+        } else if rest.starts_with('[') {
+            position += space + group_len(rest, '[', ']');
+        } else {
+            return position;
+        }
+    }
+}
+
+/// Returns the byte length of the optional arguments at the beginning of
+/// `input`.
+fn optional_arguments_len(input: &str) -> usize {
+    let mut position = 0;
+    loop {
+        let space = argument_space_len(&input[position..]);
+        let rest = &input[position + space..];
+        if rest.starts_with('[') {
+            position += space + group_len(rest, '[', ']');
+        } else {
+            return position;
+        }
+    }
+}
+
+/// Returns the byte offset just past the first occurrence of `pattern` in
+/// `input`, or the end of the input.
+fn past(input: &str, pattern: &str) -> usize {
+    input
+        .find(pattern)
+        .map_or(input.len(), |i| i + pattern.len())
+}
+
+/// Returns the byte offset just past the closing `$` of inline math.
+fn past_inline_math(input: &str) -> usize {
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '$' => return i + 1,
+            _ => {}
+        }
+    }
+    input.len()
+}
+
+impl Document for Latex {
+    fn segments<'t>(&self, input: &'t str) -> Vec<Segment<'t>> {
+        let mut text_ranges = vec![];
+        let mut position = 0;
+
+        while position < input.len() {
+            let rest = &input[position..];
+
+            if rest.starts_with('%') {
+                position += past(rest, "\n");
+            } else if let Some(math) = rest.strip_prefix("$$") {
+                position += 2 + past(math, "$$");
+            } else if let Some(math) = rest.strip_prefix('$') {
+                position += 1 + past_inline_math(math);
+            } else if rest.starts_with("\\(") {
+                position += past(rest, "\\)");
+            } else if rest.starts_with("\\[") {
+                position += past(rest, "\\]");
+            } else if let Some(command) = rest.strip_prefix('\\') {
+                let name_len = command
+                    .find(|c: char| !c.is_ascii_alphabetic())
+                    .unwrap_or(command.len());
+
+                if name_len == 0 {
+                    // Control symbol, e.g. `\%` or `\\`.
+                    position += 1 + command.chars().next().map_or(0, char::len_utf8);
+                    continue;
+                }
+
+                let name = &command[..name_len];
+                let mut len = 1 + name_len;
+                if command[name_len..].starts_with('*') {
+                    len += 1;
+                }
+
+                match name {
+                    "begin" => {
+                        let space = argument_space_len(&rest[len..]);
+                        let after = &rest[len + space..];
+                        if after.starts_with('{') {
+                            let group = group_len(after, '{', '}');
+                            let environment = after[1..group].strip_suffix('}').unwrap_or_default();
+                            len += space + group;
+                            if VERBATIM_ENVIRONMENTS.contains(&environment) {
+                                len += past(&rest[len..], &format!("\\end{{{environment}}}"));
+                            } else {
+                                len += arguments_len(&rest[len..]);
+                            }
+                        }
+                    }
+                    "end" => len += arguments_len(&rest[len..]),
+                    "verb" | "lstinline" => {
+                        len += optional_arguments_len(&rest[len..]);
+                        if let Some(delimiter) = rest[len..].chars().next() {
+                            let close = if delimiter == '{' { '}' } else { delimiter };
+                            let body = &rest[len + delimiter.len_utf8()..];
+                            len += delimiter.len_utf8()
+                                + body
+                                    .find(close)
+                                    .map_or(body.len(), |i| i + close.len_utf8());
+                        }
+                    }
+                    name if self.skip_commands.iter().any(|c| c == name) => {
+                        len += arguments_len(&rest[len..]);
+                    }
+                    _ => len += optional_arguments_len(&rest[len..]),
+                }
+
+                position += len;
+            } else if rest.starts_with(['{', '}', '&', '#', '^', '_', '~']) {
+                position += 1;
+            } else {
+                let len = rest
+                    .find(['%', '$', '\\', '{', '}', '&', '#', '^', '_', '~'])
+                    .unwrap_or(rest.len());
+                text_ranges.push(position..position + len);
+                position += len;
+            }
+        }
+
+        from_text_ranges(input, text_ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::document;
+
+    #[test]
+    fn latex_segments() {
+        use document::{Document, Segment};
+
+        let input = r"\documentclass{ltjsarticle}
+\usepackage[utf8]{inputenc}
+\begin{document}
+\section{導入}\label{sec:導入}
+本文は\textbf{太字}で、式$x = \text{漢字}$と\ref{sec:導入}を参照。% 注釈
+\begin{verbatim}
+漢字
+\end{verbatim}
+\verb|漢字|と\ruby{漢}{かん}字\\
+\[ \text{数式} \]
+\begin{tabular}{ll}
+表 & 値 \\
+\end{tabular}
+\myref{独自}
+\end{document}
+";
+
+        let latex = document::Latex {
+            skip_commands: vec!["myref".to_string()],
+        };
+
+        let text = |segments: Vec<Segment<'static>>| {
+            segments
+                .into_iter()
+                .filter_map(|s| match s {
+                    Segment::Text(t) if !t.trim().is_empty() => Some(t.trim()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            text(document::Latex::default().segments(input)),
+            [
+                "導入",
+                "本文は",
+                "太字",
+                "で、式",
+                "と",
+                "を参照。",
+                "と",
+                "字",
+                "表",
+                "値",
+                "独自"
+            ],
+        );
+        assert_eq!(
+            text(latex.segments(input)),
+            [
+                "ltjsarticle",
+                "inputenc",
+                "導入",
+                "sec:導入",
+                "本文は",
+                "太字",
+                "で、式",
+                "と",
+                "sec:導入",
+                "を参照。",
+                "と",
+                "漢",
+                "かん",
+                "字",
+                "表",
+                "値"
+            ],
+        );
+    }
+}
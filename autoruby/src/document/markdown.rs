@@ -0,0 +1,143 @@
+use std::ops::Range;
+
+use pulldown_cmark::{Event, LinkType, Options, Parser, Tag, TagEnd};
+
+use super::{from_text_ranges, Document, Segment};
+
+/// Markdown documents, including GFM tables.
+///
+/// Only prose text is annotated. Code spans, code blocks, HTML, link targets,
+/// image descriptions, autolinks, bare URLs and YAML/TOML front matter are
+/// left untouched.
+pub struct Markdown;
+
+fn is_url_terminator(c: char) -> bool {
+    c.is_whitespace()
+        || matches!(
+            c,
+            '<' | '、' | '。' | '「' | '」' | '『' | '』' | '（' | '）'
+        )
+}
+
+/// Removes bare URLs (which are not parsed as links) from a text
+/// range, returning the remaining prose ranges.
+fn without_bare_urls(input: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    const SCHEMES: [&str; 4] = ["https://", "http://", "mailto:", "www."];
+
+    let text = &input[range.clone()];
+    let mut ranges = vec![];
+    let mut start = 0;
+    let mut search_from = 0;
+
+    while let Some((url_start, _)) = SCHEMES
+        .iter()
+        .filter_map(|scheme| {
+            text[search_from..]
+                .find(scheme)
+                .map(|i| (search_from + i, scheme))
+        })
+        .min_by_key(|(i, _)| *i)
+    {
+        let url_end = text[url_start..]
+            .find(is_url_terminator)
+            .map_or(text.len(), |i| url_start + i);
+        ranges.push(range.start + start..range.start + url_start);
+        start = url_end;
+        search_from = url_end;
+    }
+
+    ranges.push(range.start + start..range.end);
+    ranges
+}
+
+impl Document for Markdown {
+    fn segments<'t>(&self, input: &'t str) -> Vec<Segment<'t>> {
+        let options = Options::ENABLE_TABLES
+            | Options::ENABLE_FOOTNOTES
+            | Options::ENABLE_STRIKETHROUGH
+            | Options::ENABLE_TASKLISTS
+            | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS
+            | Options::ENABLE_PLUSES_DELIMITED_METADATA_BLOCKS;
+
+        // Number of currently open elements whose contents must not be annotated.
+        let mut skip_depth = 0usize;
+        // Whether each currently open link is skipped.
+        let mut links = vec![];
+        let mut text_ranges = vec![];
+
+        for (event, range) in Parser::new_ext(input, options).into_offset_iter() {
+            match event {
+                Event::Start(
+                    Tag::CodeBlock(_) | Tag::MetadataBlock(_) | Tag::HtmlBlock | Tag::Image { .. },
+                ) => skip_depth += 1,
+                Event::End(
+                    TagEnd::CodeBlock
+                    | TagEnd::MetadataBlock(_)
+                    | TagEnd::HtmlBlock
+                    | TagEnd::Image,
+                ) => skip_depth -= 1,
+                Event::Start(Tag::Link { link_type, .. }) => {
+                    let skip = matches!(link_type, LinkType::Autolink | LinkType::Email);
+                    skip_depth += usize::from(skip);
+                    links.push(skip);
+                }
+                Event::End(TagEnd::Link) => {
+                    skip_depth -= usize::from(links.pop().unwrap_or_default());
+                }
+                Event::Text(_) if skip_depth == 0 => {
+                    text_ranges.extend(without_bare_urls(input, range));
+                }
+                _ => {}
+            }
+        }
+
+        from_text_ranges(input, text_ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::document;
+
+    #[test]
+    fn markdown_segments() {
+        use document::{Document, Segment};
+
+        let input = "---\ntitle: 漢字\n---\n\n# 見出し\n\n本文と`コード`と[リンク](https://example.com/漢字)。\n\n<!-- 注釈 -->\n\n```\n漢字\n```\n\n| 表 | 列 |\n|---|---|\n| 値 | <https://example.com/値> |\n\n参照 https://example.com/漢字 まで\n";
+
+        let segments = document::Markdown.segments(input);
+
+        assert_eq!(
+            segments
+                .iter()
+                .map(|s| match s {
+                    Segment::Text(t) | Segment::Verbatim(t) => *t,
+                })
+                .collect::<String>(),
+            input,
+        );
+        assert_eq!(
+            segments
+                .into_iter()
+                .filter_map(|s| match s {
+                    Segment::Text(t) => Some(t),
+                    Segment::Verbatim(_) => None,
+                })
+                .collect::<Vec<_>>(),
+            [
+                "見出し",
+                "本文と",
+                "と",
+                "リンク",
+                "。",
+                "表",
+                "列",
+                "値",
+                "参照 ",
+                " まで"
+            ],
+        );
+    }
+}
@@ -1,11 +1,167 @@
 //! Annotation formatting.
 
+use std::fmt::Write;
+
 use wana_kana::ConvertJapanese;
 
+use crate::{
+    annotate::{AnnotatedText, AnnotatedTextFragment},
+    chars::is_kanji,
+    document::{Document, Plain, Segment},
+    parse::{self, Chunk},
+};
+
+use self::escape::Escape;
+
 /// Format annotations.
 pub trait Format {
     /// Formats the given base text with annotation text.
     fn format(&self, base: &str, text: &str) -> String;
+
+    /// Formats the given base text with annotation text, given the character
+    /// immediately preceding the base in the output, if any.
+    ///
+    /// Formats whose syntax depends on the surrounding text (e.g. [`Aozora`])
+    /// override this method. By default, it delegates to [`Format::format`].
+    fn format_after(&self, preceding: Option<char>, base: &str, text: &str) -> String {
+        let _ = preceding;
+        self.format(base, text)
+    }
+
+    /// Formats adjacent pairs of base text and annotation text as a single
+    /// unit that keeps the individual annotations (jukugo ruby), given the
+    /// character immediately preceding the first base in the output, if any.
+    ///
+    /// By default, each pair is formatted separately with
+    /// [`Format::format_after`].
+    fn format_jukugo(&self, preceding: Option<char>, pairs: &[(&str, &str)]) -> String {
+        let mut output = String::new();
+        for (base, text) in pairs {
+            let preceding = output.chars().next_back().or(preceding);
+            output.push_str(&self.format_after(preceding, base, text));
+        }
+        output
+    }
+}
+
+/// Parse annotations back out of formatted text.
+pub trait Parse {
+    /// Parses text containing annotations in this format. Annotated text
+    /// becomes fragments with a single annotation containing the readings
+    /// found in the input; all other text becomes plain fragments.
+    fn parse<'t>(&self, input: &'t str) -> AnnotatedText<'t>;
+
+    /// Parses text like [`Parse::parse`], but locks the annotated fragments,
+    /// so that their original markup is rendered verbatim.
+    fn parse_locked<'t>(&self, input: &'t str) -> AnnotatedText<'t>;
+
+    /// Removes all annotations in this format from the input, leaving only
+    /// the base text.
+    fn strip(&self, input: &str) -> String {
+        self.strip_document(input, &Plain)
+    }
+
+    /// Removes the annotations in this format from the prose of a document,
+    /// leaving only the base text. Annotations that lie within a single
+    /// verbatim segment (e.g. in code) are left untouched.
+    ///
+    /// The markup of the annotations themselves must not be skipped by the
+    /// document, e.g. `ruby` must be removed from the
+    /// [`skip_elements`](crate::document::Html::skip_elements) of an HTML
+    /// document, and from the
+    /// [`skip_commands`](crate::document::Latex::skip_commands) of a LaTeX
+    /// document.
+    fn strip_document(&self, input: &str, document: &dyn Document) -> String;
+}
+
+/// Builds annotated text from chunks, merging directly adjacent annotations
+/// (e.g. `[計]{けい}[算]{さん}`) into a single fragment. If `locked`, the
+/// annotated fragments keep their original markup. Annotations too long for
+/// reading spans are left as plain base text.
+fn from_chunks(chunks: Vec<Chunk<'_>>, locked: bool) -> AnnotatedText<'_> {
+    let mut fragments = vec![];
+    let mut pairs = vec![];
+    let mut source = String::new();
+
+    let flush = |fragments: &mut Vec<_>, pairs: &mut Vec<(String, String)>, source: &mut String| {
+        if !pairs.is_empty() {
+            let mut fragment = AnnotatedTextFragment::from_pairs(pairs).unwrap_or_else(|| {
+                let text = pairs
+                    .iter()
+                    .map(|(base, _)| base.as_str())
+                    .collect::<String>();
+                AnnotatedTextFragment::plain(text.into())
+            });
+            if locked {
+                fragment.verbatim = Some(std::mem::take(source).into());
+            }
+            fragments.push(fragment);
+            pairs.clear();
+            source.clear();
+        }
+    };
+
+    for chunk in chunks {
+        match chunk {
+            Chunk::Ruby {
+                source: s,
+                pairs: p,
+            } => {
+                pairs.extend(p);
+                source.push_str(s);
+            }
+            Chunk::Plain(text) => {
+                flush(&mut fragments, &mut pairs, &mut source);
+                fragments.push(AnnotatedTextFragment::plain(text.into()));
+            }
+        }
+    }
+
+    flush(&mut fragments, &mut pairs, &mut source);
+
+    AnnotatedText { fragments }
+}
+
+/// Concatenates chunks, replacing annotations that overlap a text segment
+/// with their (escaped) base text.
+fn strip_chunks(chunks: Vec<Chunk<'_>>, segments: &[Segment<'_>], escape: &dyn Escape) -> String {
+    let mut text_ranges = vec![];
+    let mut position = 0;
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => {
+                text_ranges.push(position..position + text.len());
+                position += text.len();
+            }
+            Segment::Verbatim(text) => position += text.len(),
+        }
+    }
+
+    let mut output = String::new();
+    let mut position = 0;
+    for chunk in chunks {
+        match chunk {
+            Chunk::Plain(text) => {
+                output.push_str(text);
+                position += text.len();
+            }
+            Chunk::Ruby { source, pairs } => {
+                let range = position..position + source.len();
+                position = range.end;
+                if text_ranges
+                    .iter()
+                    .any(|text| text.start < range.end && range.start < text.end)
+                {
+                    for (base, _) in pairs {
+                        output.push_str(&escape.escape(&base));
+                    }
+                } else {
+                    output.push_str(source);
+                }
+            }
+        }
+    }
+    output
 }
 
 /// Markdown annotation formatting.
@@ -13,8 +169,50 @@ pub struct Markdown;
 
 impl Format for Markdown {
     fn format(&self, base: &str, text: &str) -> String {
+        let base = escape::Markdown.escape(base);
+        let text = escape::Markdown.escape(text);
         format!("[{base}]{{{text}}}")
     }
+
+    /// Formats jukugo ruby as `[計算]{けい|さん}`, which requires every base
+    /// to be a single character.
+    fn format_jukugo(&self, _preceding: Option<char>, pairs: &[(&str, &str)]) -> String {
+        if !pairs.iter().all(|(base, _)| base.chars().count() == 1) {
+            return pairs
+                .iter()
+                .map(|(base, text)| self.format(base, text))
+                .collect();
+        }
+
+        let base = pairs
+            .iter()
+            .map(|(base, _)| escape::Markdown.escape(base))
+            .collect::<String>();
+        let text = pairs
+            .iter()
+            .map(|(_, text)| escape::Markdown.escape(text))
+            .collect::<Vec<_>>()
+            .join("|");
+        format!("[{base}]{{{text}}}")
+    }
+}
+
+impl Parse for Markdown {
+    fn parse<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::chunks(input, parse::markdown_ruby), false)
+    }
+
+    fn parse_locked<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::chunks(input, parse::markdown_ruby), true)
+    }
+
+    fn strip_document(&self, input: &str, document: &dyn Document) -> String {
+        strip_chunks(
+            parse::chunks(input, parse::markdown_ruby),
+            &document.segments(input),
+            &escape::Markdown,
+        )
+    }
 }
 
 /// HTML annotation formatting.
@@ -22,8 +220,40 @@ pub struct Html;
 
 impl Format for Html {
     fn format(&self, base: &str, text: &str) -> String {
+        let base = escape::Html.escape(base);
+        let text = escape::Html.escape(text);
         format!("<ruby>{base}<rp>(</rp><rt>{text}</rt><rp>)</rp></ruby>")
     }
+
+    /// Formats jukugo ruby as a single `<ruby>` element with one `<rb>` and
+    /// one `<rt>` per pair.
+    fn format_jukugo(&self, _preceding: Option<char>, pairs: &[(&str, &str)]) -> String {
+        let mut bases = String::new();
+        let mut texts = String::new();
+        for (base, text) in pairs {
+            let _ = write!(bases, "<rb>{}</rb>", escape::Html.escape(base));
+            let _ = write!(texts, "<rt>{}</rt>", escape::Html.escape(text));
+        }
+        format!("<ruby>{bases}<rp>(</rp>{texts}<rp>)</rp></ruby>")
+    }
+}
+
+impl Parse for Html {
+    fn parse<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::chunks(input, parse::html_ruby), false)
+    }
+
+    fn parse_locked<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::chunks(input, parse::html_ruby), true)
+    }
+
+    fn strip_document(&self, input: &str, document: &dyn Document) -> String {
+        strip_chunks(
+            parse::chunks(input, parse::html_ruby),
+            &document.segments(input),
+            &escape::Html,
+        )
+    }
 }
 
 /// LaTeX annotation formatting.
@@ -31,15 +261,349 @@ pub struct Latex;
 
 impl Format for Latex {
     fn format(&self, base: &str, text: &str) -> String {
+        let base = escape::Latex.escape(base);
+        let text = escape::Latex.escape(text);
         format!("\\ruby{{{base}}}{{{text}}}")
     }
+
+    /// Formats jukugo ruby in `pxrubrica` syntax, e.g.
+    /// `\ruby[j]{計|算}{けい|さん}`.
+    fn format_jukugo(&self, _preceding: Option<char>, pairs: &[(&str, &str)]) -> String {
+        let join = |texts: Vec<_>| texts.join("|");
+        let base = join(
+            pairs
+                .iter()
+                .map(|(base, _)| escape::Latex.escape(base))
+                .collect(),
+        );
+        let text = join(
+            pairs
+                .iter()
+                .map(|(_, text)| escape::Latex.escape(text))
+                .collect(),
+        );
+        format!("\\ruby[j]{{{base}}}{{{text}}}")
+    }
+}
+
+impl Parse for Latex {
+    fn parse<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::chunks(input, parse::latex_ruby), false)
+    }
+
+    fn parse_locked<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::chunks(input, parse::latex_ruby), true)
+    }
+
+    fn strip_document(&self, input: &str, document: &dyn Document) -> String {
+        strip_chunks(
+            parse::chunks(input, parse::latex_ruby),
+            &document.segments(input),
+            &escape::Latex,
+        )
+    }
+}
+
+/// Aozora Bunko annotation formatting, e.g. `｜漢字《かんじ》`.
+///
+/// The `｜` delimiter is omitted when the base is a run of kanji that is not
+/// preceded by another kanji, since the extent of the base is then
+/// unambiguous.
+pub struct Aozora;
+
+impl Format for Aozora {
+    fn format(&self, base: &str, text: &str) -> String {
+        self.format_after(None, base, text)
+    }
+
+    fn format_after(&self, preceding: Option<char>, base: &str, text: &str) -> String {
+        let needs_delimiter = !base.chars().all(is_kanji) || preceding.is_some_and(is_kanji);
+        let delimiter = if needs_delimiter { "｜" } else { "" };
+        let base = escape::Aozora.escape(base);
+        let text = escape::Aozora.escape(text);
+        format!("{delimiter}{base}《{text}》")
+    }
+}
+
+impl Parse for Aozora {
+    fn parse<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::aozora_chunks(input), false)
+    }
+
+    fn parse_locked<'t>(&self, input: &'t str) -> AnnotatedText<'t> {
+        from_chunks(parse::aozora_chunks(input), true)
+    }
+
+    fn strip_document(&self, input: &str, document: &dyn Document) -> String {
+        strip_chunks(
+            parse::aozora_chunks(input),
+            &document.segments(input),
+            &escape::Aozora,
+        )
+    }
 }
 
 /// Converts the annotation text to katakana.
 pub struct WithKatakana<'a>(pub &'a dyn Format);
 
-impl<'a> Format for WithKatakana<'a> {
+impl Format for WithKatakana<'_> {
     fn format(&self, base: &str, text: &str) -> String {
         self.0.format(base, &text.to_katakana())
     }
+
+    fn format_after(&self, preceding: Option<char>, base: &str, text: &str) -> String {
+        self.0.format_after(preceding, base, &text.to_katakana())
+    }
+
+    fn format_jukugo(&self, preceding: Option<char>, pairs: &[(&str, &str)]) -> String {
+        let texts = pairs
+            .iter()
+            .map(|(_, text)| text.to_katakana())
+            .collect::<Vec<_>>();
+        let pairs = pairs
+            .iter()
+            .zip(&texts)
+            .map(|((base, _), text)| (*base, text.as_str()))
+            .collect::<Vec<_>>();
+        self.0.format_jukugo(preceding, &pairs)
+    }
+}
+
+pub mod escape {
+    //! Escaping of base and annotation text.
+    //!
+    //! Custom [`Format`](super::Format) implementations can reuse these
+    //! policies to produce well-formed output.
+
+    use std::borrow::Cow;
+
+    /// Escapes text for inclusion in formatted output.
+    pub trait Escape {
+        /// Escapes the given text. Returns the input unchanged if nothing
+        /// needs to be escaped.
+        fn escape<'s>(&self, text: &'s str) -> Cow<'s, str>;
+    }
+
+    /// Replaces each character for which `replacement` returns `Some`.
+    pub fn replace_chars(
+        text: &str,
+        replacement: impl Fn(char) -> Option<&'static str>,
+    ) -> Cow<'_, str> {
+        if !text.chars().any(|c| replacement(c).is_some()) {
+            return Cow::Borrowed(text);
+        }
+
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match replacement(c) {
+                Some(r) => escaped.push_str(r),
+                None => escaped.push(c),
+            }
+        }
+        Cow::Owned(escaped)
+    }
+
+    /// Escapes Markdown punctuation with backslashes.
+    pub struct Markdown;
+
+    impl Escape for Markdown {
+        fn escape<'s>(&self, text: &'s str) -> Cow<'s, str> {
+            replace_chars(text, |c| match c {
+                '\\' => Some("\\\\"),
+                '[' => Some("\\["),
+                ']' => Some("\\]"),
+                '{' => Some("\\{"),
+                '}' => Some("\\}"),
+                '|' => Some("\\|"),
+                '*' => Some("\\*"),
+                '_' => Some("\\_"),
+                '`' => Some("\\`"),
+                '<' => Some("\\<"),
+                '&' => Some("\\&"),
+                _ => None,
+            })
+        }
+    }
+
+    /// Escapes HTML special characters with character references.
+    pub struct Html;
+
+    impl Escape for Html {
+        fn escape<'s>(&self, text: &'s str) -> Cow<'s, str> {
+            replace_chars(text, |c| match c {
+                '&' => Some("&amp;"),
+                '<' => Some("&lt;"),
+                '>' => Some("&gt;"),
+                '"' => Some("&quot;"),
+                '\'' => Some("&#39;"),
+                _ => None,
+            })
+        }
+    }
+
+    /// Escapes LaTeX special characters.
+    pub struct Latex;
+
+    impl Escape for Latex {
+        fn escape<'s>(&self, text: &'s str) -> Cow<'s, str> {
+            replace_chars(text, |c| match c {
+                '\\' => Some("\\textbackslash{}"),
+                '{' => Some("\\{"),
+                '}' => Some("\\}"),
+                '%' => Some("\\%"),
+                '$' => Some("\\$"),
+                '#' => Some("\\#"),
+                '&' => Some("\\&"),
+                '_' => Some("\\_"),
+                '^' => Some("\\textasciicircum{}"),
+                '~' => Some("\\textasciitilde{}"),
+                '|' => Some("\\textbar{}"),
+                _ => None,
+            })
+        }
+    }
+
+    /// Escapes Aozora Bunko ruby delimiters with gaiji notation.
+    pub struct Aozora;
+
+    impl Escape for Aozora {
+        fn escape<'s>(&self, text: &'s str) -> Cow<'s, str> {
+            replace_chars(text, |c| match c {
+                '｜' => Some("※［＃縦線、1-1-35］"),
+                '《' => Some("※［＃始め二重山括弧、1-1-52］"),
+                '》' => Some("※［＃終わり二重山括弧、1-1-53］"),
+                _ => None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::{document, format};
+
+    #[test]
+    fn escaping() {
+        use format::Format;
+
+        let tests: [(&dyn Format, &str, &str, &str); 3] = [
+            (&format::Markdown, "a]b{c}", "[x]", r"[a\]b\{c\}]{\[x\]}"),
+            (
+                &format::Html,
+                "a]b{c}",
+                "<x>&\"",
+                "<ruby>a]b{c}<rp>(</rp><rt>&lt;x&gt;&amp;&quot;</rt><rp>)</rp></ruby>",
+            ),
+            (
+                &format::Latex,
+                "a]b{c}",
+                "%\\$^~",
+                r"\ruby{a]b\{c\}}{\%\textbackslash{}\$\textasciicircum{}\textasciitilde{}}",
+            ),
+        ];
+
+        for (format, base, text, expected) in tests {
+            assert_eq!(format.format(base, text), expected);
+        }
+    }
+
+    #[test]
+    fn aozora() {
+        use format::Format;
+
+        assert_eq!(format::Aozora.format("漢字", "かんじ"), "漢字《かんじ》");
+        assert_eq!(format::Aozora.format("お茶", "おちゃ"), "｜お茶《おちゃ》");
+        assert_eq!(
+            format::Aozora.format_after(Some('東'), "京", "きょう"),
+            "｜京《きょう》",
+        );
+        assert_eq!(
+            format::Aozora.format_after(Some('》'), "算", "さん"),
+            "算《さん》",
+        );
+        assert_eq!(
+            format::Aozora.format("《引用》", "いんよう"),
+            "｜※［＃始め二重山括弧、1-1-52］引用※［＃終わり二重山括弧、1-1-53］《いんよう》",
+        );
+
+        // A stray delimiter does not extend the base past the end of its line
+        // or past a following annotation.
+        for input in [
+            "a｜b\n漢字《かんじ》",
+            "a｜b《び》c\n漢字《かんじ》",
+            "｜a《え》b漢字《かんじ》",
+        ] {
+            let parsed = format::Parse::parse(&format::Aozora, input);
+            assert_eq!(
+                parsed.fragments.last().map(|f| f.text.as_ref()),
+                Some("漢字"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn strip() {
+        use format::Parse;
+
+        let tests: [(&dyn Parse, &str, &str); 4] = [
+            (
+                &format::Markdown,
+                r"[\[漢\]]{かん}[字]{じ}と`[x]`",
+                r"\[漢\]字と`[x]`",
+            ),
+            (
+                &format::Html,
+                r#"<p class="a"><ruby>&lt;漢&gt;<rt>かん</rt></ruby>字</p>"#,
+                r#"<p class="a">&lt;漢&gt;字</p>"#,
+            ),
+            (
+                &format::Latex,
+                r"\textbf{\ruby{漢}{かん}}字",
+                r"\textbf{漢}字",
+            ),
+            (
+                &format::Aozora,
+                "｜お茶《おちゃ》と漢字《かんじ》",
+                "お茶と漢字",
+            ),
+        ];
+
+        for (format, input, expected) in tests {
+            assert_eq!(format.strip(input), expected);
+        }
+
+        // Annotations in code are left untouched in documents.
+        let mut html = document::Html::default();
+        html.skip_elements
+            .retain(|e| !matches!(e.as_str(), "ruby" | "rb" | "rt" | "rp" | "rtc"));
+        let mut latex = document::Latex::default();
+        latex.skip_commands.retain(|c| c != "ruby");
+        let tests: [(&dyn Parse, &dyn document::Document, &str, &str); 3] = [
+            (
+                &format::Markdown,
+                &document::Markdown,
+                "[漢]{かん}字と`[漢]{かん}`\n\n```\n[字]{じ}\n```\n",
+                "漢字と`[漢]{かん}`\n\n```\n[字]{じ}\n```\n",
+            ),
+            (
+                &format::Html,
+                &html,
+                "<p><ruby>漢<rt>かん</rt></ruby>字</p><pre><ruby>字<rt>じ</rt></ruby></pre>",
+                "<p>漢字</p><pre><ruby>字<rt>じ</rt></ruby></pre>",
+            ),
+            (
+                &format::Latex,
+                &latex,
+                "\\ruby{漢}{かん}字\\verb|\\ruby{字}{じ}|",
+                "漢字\\verb|\\ruby{字}{じ}|",
+            ),
+        ];
+
+        for (format, document, input, expected) in tests {
+            assert_eq!(format.strip_document(input, document), expected);
+        }
+    }
 }
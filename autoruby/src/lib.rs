@@ -2,19 +2,33 @@
 #![warn(clippy::pedantic)]
 #![warn(missing_docs)]
 
-use dictionary::Dictionary;
-
+/// Returns the integrated dictionary, e.g. to use as the base of a
+/// [`dictionary::LayeredDictionary`]. It is queried in place, so it is
+/// cheap to load.
+///
+/// # Panics
+///
+/// Panics if the embedded dictionary is invalid, which the build script
+/// rules out.
 #[cfg(feature = "integrated")]
-static DICTIONARY: once_cell::sync::Lazy<Dictionary> = once_cell::sync::Lazy::new(|| {
-    let dict_bytes = include_bytes!(concat!(env!("OUT_DIR"), "/dict.bin"));
-    let dictionary: Dictionary = bincode::deserialize(dict_bytes).unwrap();
-
-    dictionary
-});
+#[must_use]
+pub fn integrated_dictionary() -> &'static dictionary::CompactDictionary {
+    static DICTIONARY: once_cell::sync::OnceCell<dictionary::CompactDictionary> =
+        once_cell::sync::OnceCell::new();
+    DICTIONARY.get_or_init(|| {
+        let dict_bytes = include_bytes!(concat!(env!("OUT_DIR"), "/dict.ardc"));
+        dictionary::CompactDictionary::from_static(dict_bytes).unwrap()
+    })
+}
 
+mod align;
 pub mod annotate;
+mod chars;
+mod compact;
 pub mod dictionary;
+pub mod document;
 pub mod format;
+mod normalize;
 mod parse;
 pub mod select;
 
@@ -22,7 +36,7 @@ pub mod select;
 mod tests {
     use pretty_assertions::assert_eq;
 
-    use crate::{annotate, format, select};
+    use crate::{annotate, dictionary, document, format, parse, select};
 
     struct Test<'a> {
         input: &'a str,
@@ -86,9 +100,656 @@ mod tests {
     }
 
     #[test]
-    #[ignore = "lack of dictionary support"]
-    fn place_names() {
-        let tests = vec![
+    fn inflections() {
+        let tests = [
+            ("来ない", "[来]{こ}ない"),
+            ("来ます", "[来]{き}ます"),
+            ("来た", "[来]{き}た"),
+            ("行った", "[行]{い}った"),
+            ("勉強した", "[勉]{べん}[強]{きょう}した"),
+        ];
+
+        let annotator = annotate::Annotator::new_with_integrated_dictionary();
+
+        for (text, expected) in tests {
+            let actual = annotator
+                .annotate(text)
+                .render(&select::heuristic::All, &format::Markdown);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn kanji_readings() {
+        use dictionary::Lookup;
+
+        assert!(crate::integrated_dictionary()
+            .kanji_readings('有')
+            .iter()
+            .any(|r| r == "あ"));
+    }
+
+    #[test]
+    fn names() {
+        use dictionary::{Lookup, NameType};
+
+        let mut dictionary = dictionary::build(
+            "東|ひがし|0:ひがし\n東京|とうきょう|0:とう;1:きょう\n都|と|0:と\n".as_bytes(),
+        )
+        .unwrap();
+
+        dictionary.add_names(
+            "<JMnedict>
+<entry>
+<ent_seq>1</ent_seq>
+<k_ele><keb>東京都</keb></k_ele>
+<r_ele><reb>とうきょうと</reb></r_ele>
+<trans><name_type>&place;</name_type><trans_det>Tokyo Metropolis</trans_det></trans>
+</entry>
+<entry>
+<ent_seq>2</ent_seq>
+<k_ele><keb>東</keb></k_ele>
+<r_ele><reb>ひがし</reb></r_ele>
+<r_ele><reb>あずま</reb></r_ele>
+<trans><name_type>&surname;</name_type><name_type>&place;</name_type></trans>
+</entry>
+</JMnedict>",
+        );
+
+        let entry = dictionary.lookup_word("東京都").next().unwrap();
+        assert_eq!(entry.name_types, [NameType::Place]);
+        assert_eq!(
+            entry
+                .reading_spans
+                .iter()
+                .map(|s| s.text.as_str())
+                .collect::<Vec<_>>(),
+            ["とう", "きょう", "と"],
+        );
+
+        let entries = dictionary
+            .lookup_word("東")
+            .map(|e| (e.reading.as_str(), e.name_types.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                ("あずま", vec![NameType::Surname, NameType::Place]),
+                ("ひがし", vec![NameType::Surname, NameType::Place]),
+            ],
+        );
+        assert!(dictionary
+            .kanji_readings('東')
+            .iter()
+            .any(|r| r == "あずま"));
+
+        let selector =
+            select::filter::ProperNouns::new(select::heuristic::All).of_type(NameType::Place);
+        for (word, expected) in [("東京都", true), ("東京", false)] {
+            let fragment = annotate::AnnotatedTextFragment {
+                text: word.into(),
+                annotations: dictionary
+                    .lookup_word(word)
+                    .map(std::borrow::Cow::Borrowed)
+                    .collect(),
+                verbatim: None,
+            };
+            assert_eq!(
+                select::Select::select(&selector, &fragment).is_some(),
+                expected
+            );
+        }
+
+        // Names missing from the dictionary have guessed readings.
+        let selector = select::filter::Confident::new(select::heuristic::All);
+        for (word, expected) in [("東京都", false), ("東京", true)] {
+            let fragment = annotate::AnnotatedTextFragment {
+                text: word.into(),
+                annotations: dictionary
+                    .lookup_word(word)
+                    .map(std::borrow::Cow::Borrowed)
+                    .collect(),
+                verbatim: None,
+            };
+            assert_eq!(
+                select::Select::select(&selector, &fragment).is_some(),
+                expected
+            );
+        }
+
+        let mut bytes = vec![];
+        dictionary.write_compact(&mut bytes).unwrap();
+        let compact = dictionary::CompactDictionary::from_bytes(bytes).unwrap();
+        assert!(compact.lookup_word("東京都").next().unwrap().low_confidence);
+
+        let mut binary = vec![];
+        dictionary.write_binary(&mut binary).unwrap();
+        let loaded = dictionary::Dictionary::from_reader(
+            binary.as_slice(),
+            dictionary::DictionaryFormat::Binary,
+        )
+        .unwrap();
+        assert!(loaded.lookup_word("東京都").next().unwrap().low_confidence);
+        assert!(!loaded.lookup_word("東京").next().unwrap().low_confidence);
+    }
+
+    #[test]
+    fn load_dictionary() {
+        use dictionary::{Dictionary, DictionaryFormat};
+
+        let source = "漢字|かんじ|0:かん;1:じ\n";
+        let dictionary =
+            Dictionary::from_reader(source.as_bytes(), DictionaryFormat::Source).unwrap();
+
+        let mut binary = vec![];
+        dictionary.write_binary(&mut binary).unwrap();
+        let dictionary = Dictionary::from_reader(&binary[..], DictionaryFormat::Binary).unwrap();
+
+        let entry = dictionary.lookup_word("漢字").next().unwrap();
+        assert_eq!(entry.reading, "かんじ");
+        assert_eq!(entry.reading_spans.len(), 2);
+
+        assert!(Dictionary::from_reader("漢字".as_bytes(), DictionaryFormat::Source).is_err());
+        assert!(Dictionary::from_reader(&binary[1..], DictionaryFormat::Binary).is_err());
+        assert_eq!(
+            DictionaryFormat::from_path("dict.BIN"),
+            DictionaryFormat::Binary
+        );
+        assert_eq!(
+            DictionaryFormat::from_path("JmdictFurigana.txt"),
+            DictionaryFormat::Source
+        );
+    }
+
+    #[test]
+    fn layered_dictionary() {
+        use dictionary::{Dictionary, DictionaryFormat, LayeredDictionary, Lookup};
+
+        let base = Dictionary::from_reader(
+            "明日|あした|0-1:あした\n明日|みょうにち|0:みょう;1:にち\n今日|きょう|0-1:きょう\n今日|こんにち|0:こん;1:にち\n今日中|きょうじゅう|0-1:きょう;2:じゅう\n".as_bytes(),
+            DictionaryFormat::Source,
+        )
+        .unwrap();
+
+        let mut layered = LayeredDictionary::new(&base);
+        layered
+            .add_layer("# house style\n明日|あす|0-1:あす\n-今日|こんにち\n".as_bytes())
+            .unwrap();
+        layered
+            .add_layer("-今日中\n中|なか|0:なか\n中身|なかみ|0:なか;1:み\n".as_bytes())
+            .unwrap();
+
+        let readings = |word| {
+            layered
+                .lookup_word(word)
+                .map(|e| e.reading.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(readings("明日"), ["あす"]);
+        assert_eq!(readings("今日"), ["きょう"]);
+        assert!(readings("今日中").is_empty());
+
+        let prefixed = layered
+            .lookup_prefixed("今日")
+            .map(|e| e.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(prefixed, ["今日"]);
+        let prefixed = layered
+            .lookup_prefixed("明")
+            .map(|e| e.reading.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(prefixed, ["あす"]);
+        let all = layered
+            .lookup_prefixed("")
+            .map(|e| (e.text.as_str(), e.reading.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            all,
+            [
+                ("中", "なか"),
+                ("中身", "なかみ"),
+                ("今日", "きょう"),
+                ("明日", "あす"),
+            ],
+        );
+
+        // The readings of the layers come first, then those of the base,
+        // without those of suppressed entries.
+        assert_eq!(layered.kanji_readings('中'), ["なか"]);
+        assert_eq!(base.kanji_readings('今'), ["こん"]);
+        assert!(layered.kanji_readings('今').is_empty());
+        assert_eq!(layered.kanji_readings('明'), base.kanji_readings('明'));
+
+        let mut base = base.clone();
+        base.merge(
+            dictionary::build("世界中|せかいじゅう|0:せ;1:かい;2:じゅう\n".as_bytes()).unwrap(),
+        );
+        let mut layered = LayeredDictionary::new(&base);
+        layered.add_layer("-今日中\n".as_bytes()).unwrap();
+        assert_eq!(layered.kanji_readings('中'), ["じゅう"]);
+
+        assert!(layered.add_layer("-\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn tokenizer_dictionary() {
+        use std::io::Write;
+
+        use annotate::{Annotator, TokenizerDictionary};
+        use dictionary::{Dictionary, DictionaryFormat, LayeredDictionary, TokenizerFormat};
+
+        let base = Dictionary::from_reader("".as_bytes(), DictionaryFormat::Source).unwrap();
+        let mut layered = LayeredDictionary::new(&base);
+        layered
+            .add_layer("形態素解析|けいたいそかいせき|0-2:けいたいそ;3-4:かいせき\n".as_bytes())
+            .unwrap();
+
+        let mut csv = vec![];
+        layered
+            .write_tokenizer_dictionary(&mut csv, TokenizerFormat::UniDic)
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(
+            csv,
+            "形態素解析,0,0,-10000,名詞,普通名詞,一般,*,*,*,ケイタイソカイセキ,形態素解析,形態素解析,ケイタイソカイセキ,形態素解析,ケイタイソカイセキ,*,*,*,*,*\n",
+        );
+
+        let mut ipadic = vec![];
+        layered
+            .write_tokenizer_dictionary(&mut ipadic, TokenizerFormat::Ipadic)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(ipadic).unwrap(),
+            "形態素解析,0,0,-10000,名詞,一般,*,*,*,*,形態素解析,ケイタイソカイセキ,ケイタイソカイセキ\n",
+        );
+        assert_eq!(
+            TokenizerDictionary::IpadicPath("ipadic".into()).user_dictionary_format(),
+            TokenizerFormat::Ipadic,
+        );
+
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        file.write_all(csv.as_bytes()).unwrap();
+
+        // `UniDic` splits the term, unless the user dictionary keeps it
+        // together. The base dictionary is empty, so that the annotator
+        // does not join the tokens itself.
+        let text = "形態素解析をする";
+        let first_fragment = |annotator: &Annotator| {
+            let annotated = annotator.annotate(text);
+            let fragment = &annotated.fragments[0];
+            (
+                fragment.text.to_string(),
+                fragment.annotations.first().map(|e| e.reading.clone()),
+            )
+        };
+        let split = Annotator::builder(&base).build().unwrap();
+        assert_ne!(first_fragment(&split).0, "形態素解析");
+        let joined = Annotator::builder(&base)
+            .user_dictionary(file.path())
+            .build()
+            .unwrap();
+        assert_eq!(
+            first_fragment(&joined),
+            (
+                "形態素解析".to_string(),
+                Some("けいたいそかいせき".to_string())
+            ),
+        );
+
+        let annotator = Annotator::builder(&layered)
+            .user_dictionary(file.path())
+            .build()
+            .unwrap();
+        let annotated = annotator.annotate(text);
+        assert_eq!(annotated.fragments[0].text, "形態素解析");
+        assert!(!annotated.fragments[0].annotations[0].low_confidence);
+    }
+
+    #[test]
+    fn annotator_builder() {
+        use annotate::{Annotator, Penalty, TokenizerDictionary, TokenizerError, TokenizerMode};
+
+        let dictionary = crate::integrated_dictionary();
+
+        assert!(Annotator::builder(dictionary)
+            .mode(TokenizerMode::Decompose(Penalty::default()))
+            .build()
+            .is_ok());
+
+        assert!(matches!(
+            Annotator::builder(dictionary)
+                .user_dictionary("missing.csv")
+                .build(),
+            Err(TokenizerError::UserDictionary(..)),
+        ));
+
+        assert!(matches!(
+            Annotator::builder(dictionary)
+                .tokenizer_dictionary(TokenizerDictionary::IpadicPath("missing".into()))
+                .build(),
+            Err(TokenizerError::Dictionary(_)),
+        ));
+    }
+
+    #[test]
+    fn shared_annotator() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let annotator = annotate::Annotator::new_with_integrated_dictionary();
+        assert_send_sync(&annotator);
+
+        let other = annotate::Annotator::builder(crate::integrated_dictionary())
+            .tokenizer(annotator.tokenizer().clone())
+            .build()
+            .unwrap();
+
+        let expected = annotator
+            .annotate("日本語を勉強する")
+            .render(&select::heuristic::All, &format::Markdown);
+
+        std::thread::scope(|scope| {
+            let threads = (0..4)
+                .map(|i| {
+                    let annotator = if i % 2 == 0 { &annotator } else { &other };
+                    scope.spawn(move || {
+                        annotator
+                            .annotate("日本語を勉強する")
+                            .render(&select::heuristic::All, &format::Markdown)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for thread in threads {
+                assert_eq!(thread.join().unwrap(), expected);
+            }
+        });
+    }
+
+    #[test]
+    fn invalid_spans() {
+        use std::borrow::Cow;
+
+        use annotate::{AnnotateError, AnnotatedText, AnnotatedTextFragment};
+
+        let entry = dictionary::tests::entry("漢字", "かんじ", &[(1, 5, "じ")]);
+        let text = AnnotatedText {
+            fragments: vec![
+                AnnotatedTextFragment::from_pairs(&[("日", "に"), ("本", "ほん")]).unwrap(),
+                AnnotatedTextFragment {
+                    text: "漢字".into(),
+                    annotations: vec![Cow::Owned(entry)],
+                    verbatim: None,
+                },
+            ],
+        };
+
+        let result = text.try_render(&select::heuristic::All, &format::Markdown);
+        assert!(matches!(
+            result,
+            Err(AnnotateError::InvalidSpan { fragment, span, .. })
+                if fragment == "漢字" && span.end_index == 5,
+        ));
+
+        assert_eq!(
+            text.render(&select::heuristic::All, &format::Markdown),
+            "[日]{に}[本]{ほん}漢字",
+        );
+    }
+
+    #[test]
+    fn export_dictionary() {
+        use dictionary::{ExportFormat, NameType};
+
+        let source = "\
+漢字|かんじ|0:かん;1:じ
+日本|にほん|0-1:にほん
+\"あ,い\"|あい|
+";
+        let dictionary = dictionary::build(source.as_bytes()).unwrap();
+        let export = |format| {
+            let mut output = vec![];
+            dictionary::export(dictionary.entries(), &mut output, format).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        let exported = export(ExportFormat::Source);
+        let mut lines = source.lines().collect::<Vec<_>>();
+        lines.sort_unstable();
+        assert_eq!(exported.lines().collect::<Vec<_>>(), lines);
+        for line in exported.lines() {
+            let (rest, _) = parse::dictionary_line(line).unwrap();
+            assert_eq!(rest, "");
+        }
+
+        assert_eq!(
+            export(ExportFormat::JsonLines).lines().next().unwrap(),
+            r#"{"text":"\"あ,い\"","text_is_common":false,"reading":"あい","reading_is_common":false,"reading_spans":[],"low_confidence":false,"name_types":[]}"#
+        );
+        assert_eq!(
+            export(ExportFormat::Csv).lines().collect::<Vec<_>>(),
+            [
+                "text,reading,text_is_common,reading_is_common,reading_spans,low_confidence,name_types",
+                r#""""あ,い""",あい,false,false,,false,"#,
+                "日本,にほん,false,false,0-1:にほん,false,",
+                "漢字,かんじ,false,false,0:かん;1:じ,false,",
+            ]
+        );
+
+        let name = dictionary::TextEntry {
+            low_confidence: true,
+            name_types: vec![NameType::Surname, NameType::Place, NameType::Other],
+            ..dictionary::tests::entry("東", "あずま", &[])
+        };
+        let export_name = |format| {
+            let mut output = vec![];
+            dictionary::export([&name], &mut output, format).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert!(export_name(ExportFormat::JsonLines)
+            .contains(r#""low_confidence":true,"name_types":["surname","place","oth"]"#));
+        assert!(export_name(ExportFormat::Csv).ends_with(",true,surname;place;oth\n"));
+        for name_type in &name.name_types {
+            assert_eq!(NameType::from_jmnedict(name_type.as_str()), *name_type);
+        }
+
+        let entry = dictionary::tests::entry("a|b", "ab", &[]);
+        assert!(dictionary::export([&entry], vec![], ExportFormat::Source).is_err());
+    }
+
+    #[test]
+    fn lookup_reading() {
+        use dictionary::{CompactDictionary, Lookup};
+
+        let source = "\
+会う|あう|0:あ
+合う|あう|0:あ
+遭う|あう|0:あ
+青|あお|0:あお
+コーヒー|コーヒー|
+";
+        let mut dictionary = dictionary::build(source.as_bytes()).unwrap();
+        dictionary.add_frequencies(
+            "<entry><k_ele><keb>合う</keb><ke_pri>ichi1</ke_pri></k_ele><r_ele><reb>あう</reb></r_ele></entry>",
+        );
+        let mut bytes = vec![];
+        dictionary.write_compact(&mut bytes).unwrap();
+        let compact = CompactDictionary::from_bytes(bytes).unwrap();
+
+        let texts = |entries: &mut dyn Iterator<Item = &dictionary::TextEntry>| {
+            entries.map(|e| e.text.clone()).collect::<Vec<_>>()
+        };
+        for reading in ["あう", "アウ"] {
+            assert_eq!(
+                texts(&mut dictionary.lookup_reading(reading)),
+                ["合う", "会う", "遭う"]
+            );
+            assert_eq!(
+                texts(&mut compact.lookup_reading(reading)),
+                ["合う", "会う", "遭う"]
+            );
+        }
+        assert_eq!(
+            texts(&mut dictionary.lookup_reading_prefixed("あ")),
+            ["合う", "会う", "遭う", "青"]
+        );
+        assert_eq!(
+            texts(&mut compact.lookup_reading_prefixed("ア")),
+            ["合う", "会う", "遭う", "青"]
+        );
+        assert_eq!(texts(&mut compact.lookup_reading("こーひー")), ["コーヒー"]);
+        assert_eq!(
+            texts(&mut dictionary.lookup_reading("コーヒー")),
+            ["コーヒー"]
+        );
+        assert!(dictionary.lookup_reading("かう").next().is_none());
+
+        dictionary.merge(dictionary::build("買う|かう|0:か\n".as_bytes()).unwrap());
+        assert_eq!(texts(&mut dictionary.lookup_reading("かう")), ["買う"]);
+
+        let mut layered = dictionary::LayeredDictionary::new(&compact);
+        layered
+            .add_layer("-遭う\n会う|おう|0:お\n仰ぐ|あおぐ|0:あお\n".as_bytes())
+            .unwrap();
+        let layered: &dyn Lookup = &layered;
+        assert_eq!(texts(&mut layered.lookup_reading("あう")), ["合う"]);
+        assert_eq!(texts(&mut layered.lookup_reading("おう")), ["会う"]);
+        assert_eq!(
+            texts(&mut layered.lookup_reading_prefixed("あ")),
+            ["合う", "青", "仰ぐ"]
+        );
+    }
+
+    #[test]
+    fn merge_dictionaries() {
+        use dictionary::Statistics;
+
+        let mut dictionary =
+            dictionary::build("漢字|かんじ|0:かん;1:じ\n日本|にほん|0-1:にほん\n".as_bytes())
+                .unwrap();
+        let other = dictionary::build(
+            "日本|にほん|0:に;1:ほん\n日本|にっぽん|0:にっ;1:ぽん\n生|なま|0:なま\n".as_bytes(),
+        )
+        .unwrap();
+        dictionary.merge(other);
+
+        let spans = dictionary
+            .lookup_word("日本")
+            .map(|e| e.reading_spans.len())
+            .collect::<Vec<_>>();
+        assert_eq!(spans, [2, 2]);
+
+        let jmdict = "<JMdict>
+<entry>
+<k_ele><keb>日本</keb><ke_pri>news1</ke_pri></k_ele>
+<r_ele><reb>にほん</reb><re_pri>news1</re_pri></r_ele>
+<r_ele><reb>にっぽん</reb></r_ele>
+</entry>
+<entry>
+<k_ele><keb>生</keb></k_ele>
+<k_ele><keb>生り</keb></k_ele>
+<r_ele><reb>なま</reb><re_restr>生り</re_restr><re_pri>ichi1</re_pri></r_ele>
+<r_ele><reb>き</reb><re_nokanji/><re_pri>ichi1</re_pri></r_ele>
+</entry>
+</JMdict>";
+        dictionary.add_frequencies(jmdict);
+
+        let common = |word, reading| {
+            dictionary
+                .lookup_word(word)
+                .find(|e| e.reading == reading)
+                .map(|e| (e.text_is_common, e.reading_is_common))
+        };
+        assert_eq!(common("日本", "にほん"), Some((true, true)));
+        assert_eq!(common("日本", "にっぽん"), Some((true, false)));
+        assert_eq!(common("生", "なま"), Some((false, false)));
+
+        assert_eq!(
+            dictionary.statistics(),
+            Statistics {
+                entries: 4,
+                words: 3,
+                common: 2,
+                names: 0,
+                kanji: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn render_modes() {
+        use annotate::{AnnotatedText, AnnotatedTextFragment, RenderMode};
+        use format::Format;
+
+        let text = AnnotatedText {
+            fragments: vec![
+                AnnotatedTextFragment::from_pairs(&[("計", "けい"), ("算", "さん")]).unwrap(),
+                AnnotatedTextFragment::plain("する".into()),
+            ],
+        };
+
+        let tests: [(&dyn Format, RenderMode, &str); 7] = [
+            (&format::Markdown, RenderMode::Mono, "[計]{けい}[算]{さん}する"),
+            (&format::Markdown, RenderMode::Group, "[計算]{けいさん}する"),
+            (&format::Markdown, RenderMode::Jukugo, "[計算]{けい|さん}する"),
+            (
+                &format::Html,
+                RenderMode::Jukugo,
+                "<ruby><rb>計</rb><rb>算</rb><rp>(</rp><rt>けい</rt><rt>さん</rt><rp>)</rp></ruby>する",
+            ),
+            (&format::Latex, RenderMode::Jukugo, r"\ruby[j]{計|算}{けい|さん}する"),
+            (&format::Aozora, RenderMode::Group, "計算《けいさん》する"),
+            (&format::Aozora, RenderMode::Jukugo, "計《けい》算《さん》する"),
+        ];
+
+        for (format, mode, expected) in tests {
+            let rendered = text.render_with_mode(&select::heuristic::All, format, mode);
+            assert_eq!(rendered, expected);
+        }
+    }
+
+    #[test]
+    fn preserve_existing() {
+        fn check<P: format::Parse + format::Format + Sync>(
+            format: &P,
+            document: &dyn document::Document,
+            input: &str,
+            expected: &str,
+        ) {
+            let annotator =
+                annotate::Annotator::new_with_integrated_dictionary().preserve_existing(format);
+            let actual = annotator.annotate_document(input, document).render(
+                &select::filter::FirstOccurrence::new(select::heuristic::All).counting_locked(),
+                format,
+            );
+            assert_eq!(actual, expected);
+        }
+
+        check(
+            &format::Markdown,
+            &document::Markdown,
+            "[本気]{マジ}で本気だ。簡単",
+            "[本気]{マジ}で本気だ。[簡]{かん}[単]{たん}",
+        );
+        check(
+            &format::Html,
+            &document::Html::default(),
+            "<p><ruby>本気<rt>マジ</rt></ruby>で本気</p>",
+            "<p><ruby>本気<rt>マジ</rt></ruby>で本気</p>",
+        );
+    }
+
+    #[test]
+    fn markdown_document() {
+        let annotator = annotate::Annotator::new_with_integrated_dictionary();
+        let input = "# 計算\n\n`計算`\n";
+        let actual = annotator
+            .annotate_document(input, &document::Markdown)
+            .render(&select::heuristic::All, &format::Markdown);
+        assert_eq!(actual, "# [計]{けい}[算]{さん}\n\n`計算`\n");
+    }
+
+    fn place_name_tests() -> Vec<Test<'static>> {
+        vec![
             Test {
                 input: "東京都",
                 expected_uncommon_only: "[東]{とう}[京]{きょう}[都]{と}",
@@ -109,11 +770,73 @@ mod tests {
                 expected_uncommon_only: "[福]{ふく}[岡]{おか}[市]{し}",
                 expected_all: "[福]{ふく}[岡]{おか}[市]{し}",
             },
-        ];
+        ]
+    }
+
+    #[test]
+    fn place_names() {
+        use dictionary::NameType;
 
+        // The readings of each kanji, from which those of the names are guessed
+        let furigana = "東京|とうきょう|0:とう;1:きょう
+都|と|0:と
+新|しん|0:しん
+宿題|しゅくだい|0:しゅく;1:だい
+渋|しぶ|0:しぶ
+谷|や|0:や
+福|ふく|0:ふく
+岡|おか|0:おか
+市|し|0:し
+";
+        let mut dictionary = dictionary::build(furigana.as_bytes()).unwrap();
+
+        dictionary.add_names(
+            "<JMnedict>
+<entry>
+<k_ele><keb>東京都</keb></k_ele>
+<r_ele><reb>とうきょうと</reb></r_ele>
+<trans><name_type>&place;</name_type></trans>
+</entry>
+<entry>
+<k_ele><keb>新宿</keb></k_ele>
+<r_ele><reb>しんじゅく</reb></r_ele>
+<trans><name_type>&place;</name_type></trans>
+</entry>
+<entry>
+<k_ele><keb>渋谷</keb></k_ele>
+<r_ele><reb>しぶや</reb></r_ele>
+<trans><name_type>&place;</name_type><name_type>&surname;</name_type></trans>
+</entry>
+<entry>
+<k_ele><keb>福岡市</keb></k_ele>
+<r_ele><reb>ふくおかし</reb></r_ele>
+<trans><name_type>&place;</name_type></trans>
+</entry>
+</JMnedict>",
+        );
+
+        let annotator = annotate::Annotator::builder(&dictionary).build().unwrap();
+        for test in place_name_tests() {
+            let annotated = annotator.annotate(test.input);
+            assert_eq!(annotated.fragments.len(), 1);
+            assert!(annotated.fragments[0].annotations[0]
+                .name_types
+                .contains(&NameType::Place));
+
+            let result = annotated.render(&select::heuristic::UncommonOnly, &format::Markdown);
+            assert_eq!(result, test.expected_uncommon_only);
+
+            let result = annotated.render(&select::heuristic::All, &format::Markdown);
+            assert_eq!(result, test.expected_all);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "names"), ignore = "requires the `names` feature")]
+    fn integrated_place_names() {
         let annotator = annotate::Annotator::new_with_integrated_dictionary();
 
-        for test in tests {
+        for test in place_name_tests() {
             let annotated = annotator.annotate(test.input);
             let result = annotated.render(&select::heuristic::UncommonOnly, &format::Markdown);
             assert_eq!(result, test.expected_uncommon_only);
@@ -122,4 +845,26 @@ mod tests {
             assert_eq!(result, test.expected_all);
         }
     }
+
+    #[test]
+    fn orthographic_variants() {
+        let dictionary = dictionary::build("学校|がっこう|0:がっ;1:こう\n".as_bytes()).unwrap();
+        // Readings guessed for words missing from the dictionary are left out.
+        let selector = select::filter::Confident::new(select::heuristic::All);
+
+        let annotator = annotate::Annotator::builder(&dictionary)
+            .normalize_variants(true)
+            .build()
+            .unwrap();
+        let actual = annotator
+            .annotate("學校")
+            .render(&selector, &format::Markdown);
+        assert_eq!(actual, "[學]{がっ}[校]{こう}");
+
+        let annotator = annotate::Annotator::new(&dictionary);
+        let actual = annotator
+            .annotate("學校")
+            .render(&selector, &format::Markdown);
+        assert_eq!(actual, "學校");
+    }
 }
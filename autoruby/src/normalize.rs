@@ -0,0 +1,484 @@
+//! Normalization of orthographic variants before dictionary lookup.
+
+use std::{borrow::Cow, ops::Range};
+
+use crate::{chars::is_kanji, dictionary::ReadingSpan};
+
+/// Old forms of kanji and their standard forms, sorted by the old form.
+const OLD_FORMS: &[(char, char)] = &[
+    ('亂', '乱'),
+    ('佛', '仏'),
+    ('來', '来'),
+    ('假', '仮'),
+    ('傳', '伝'),
+    ('僞', '偽'),
+    ('價', '価'),
+    ('兒', '児'),
+    ('兩', '両'),
+    ('勞', '労'),
+    ('區', '区'),
+    ('參', '参'),
+    ('國', '国'),
+    ('圓', '円'),
+    ('圖', '図'),
+    ('團', '団'),
+    ('壓', '圧'),
+    ('壞', '壊'),
+    ('壯', '壮'),
+    ('壽', '寿'),
+    ('學', '学'),
+    ('實', '実'),
+    ('寫', '写'),
+    ('將', '将'),
+    ('專', '専'),
+    ('對', '対'),
+    ('屆', '届'),
+    ('巖', '巌'),
+    ('帶', '帯'),
+    ('廢', '廃'),
+    ('廣', '広'),
+    ('廳', '庁'),
+    ('彈', '弾'),
+    ('徑', '径'),
+    ('從', '従'),
+    ('德', '徳'),
+    ('恆', '恒'),
+    ('惠', '恵'),
+    ('惡', '悪'),
+    ('應', '応'),
+    ('戰', '戦'),
+    ('拂', '払'),
+    ('擔', '担'),
+    ('據', '拠'),
+    ('擧', '挙'),
+    ('擴', '拡'),
+    ('攝', '摂'),
+    ('收', '収'),
+    ('效', '効'),
+    ('敎', '教'),
+    ('數', '数'),
+    ('斷', '断'),
+    ('晉', '晋'),
+    ('晝', '昼'),
+    ('會', '会'),
+    ('條', '条'),
+    ('榮', '栄'),
+    ('樂', '楽'),
+    ('樓', '楼'),
+    ('檢', '検'),
+    ('櫻', '桜'),
+    ('歡', '歓'),
+    ('步', '歩'),
+    ('歲', '歳'),
+    ('歷', '歴'),
+    ('歸', '帰'),
+    ('殘', '残'),
+    ('每', '毎'),
+    ('氣', '気'),
+    ('沒', '没'),
+    ('淸', '清'),
+    ('淺', '浅'),
+    ('溫', '温'),
+    ('滯', '滞'),
+    ('滿', '満'),
+    ('澁', '渋'),
+    ('澤', '沢'),
+    ('濕', '湿'),
+    ('濟', '済'),
+    ('濱', '浜'),
+    ('燈', '灯'),
+    ('燒', '焼'),
+    ('爐', '炉'),
+    ('爭', '争'),
+    ('爲', '為'),
+    ('狀', '状'),
+    ('狹', '狭'),
+    ('獨', '独'),
+    ('獸', '獣'),
+    ('瓣', '弁'),
+    ('畫', '画'),
+    ('當', '当'),
+    ('疊', '畳'),
+    ('發', '発'),
+    ('盜', '盗'),
+    ('盡', '尽'),
+    ('眞', '真'),
+    ('硏', '研'),
+    ('碎', '砕'),
+    ('祕', '秘'),
+    ('祿', '禄'),
+    ('禪', '禅'),
+    ('禮', '礼'),
+    ('稱', '称'),
+    ('稻', '稲'),
+    ('穗', '穂'),
+    ('穩', '穏'),
+    ('竊', '窃'),
+    ('粹', '粋'),
+    ('絲', '糸'),
+    ('經', '経'),
+    ('緖', '緒'),
+    ('縣', '県'),
+    ('縱', '縦'),
+    ('總', '総'),
+    ('繩', '縄'),
+    ('繪', '絵'),
+    ('繼', '継'),
+    ('續', '続'),
+    ('纖', '繊'),
+    ('缺', '欠'),
+    ('羣', '群'),
+    ('聲', '声'),
+    ('聽', '聴'),
+    ('肅', '粛'),
+    ('脫', '脱'),
+    ('腦', '脳'),
+    ('膽', '胆'),
+    ('臺', '台'),
+    ('與', '与'),
+    ('舊', '旧'),
+    ('舍', '舎'),
+    ('莊', '荘'),
+    ('莖', '茎'),
+    ('萠', '萌'),
+    ('萬', '万'),
+    ('藏', '蔵'),
+    ('藝', '芸'),
+    ('藥', '薬'),
+    ('處', '処'),
+    ('號', '号'),
+    ('螢', '蛍'),
+    ('蟲', '虫'),
+    ('蠶', '蚕'),
+    ('衞', '衛'),
+    ('裝', '装'),
+    ('襃', '褒'),
+    ('覺', '覚'),
+    ('觀', '観'),
+    ('觸', '触'),
+    ('證', '証'),
+    ('譯', '訳'),
+    ('譽', '誉'),
+    ('讀', '読'),
+    ('變', '変'),
+    ('讓', '譲'),
+    ('豐', '豊'),
+    ('豫', '予'),
+    ('貳', '弐'),
+    ('賣', '売'),
+    ('賴', '頼'),
+    ('贊', '賛'),
+    ('踐', '践'),
+    ('輕', '軽'),
+    ('轉', '転'),
+    ('辨', '弁'),
+    ('辭', '辞'),
+    ('辯', '弁'),
+    ('遞', '逓'),
+    ('遲', '遅'),
+    ('邊', '辺'),
+    ('郞', '郎'),
+    ('鄕', '郷'),
+    ('醉', '酔'),
+    ('醫', '医'),
+    ('釋', '釈'),
+    ('錄', '録'),
+    ('錢', '銭'),
+    ('鎭', '鎮'),
+    ('鐵', '鉄'),
+    ('鑛', '鉱'),
+    ('閒', '間'),
+    ('關', '関'),
+    ('陷', '陥'),
+    ('隨', '随'),
+    ('險', '険'),
+    ('隱', '隠'),
+    ('雙', '双'),
+    ('雜', '雑'),
+    ('雞', '鶏'),
+    ('霸', '覇'),
+    ('靈', '霊'),
+    ('靑', '青'),
+    ('靜', '静'),
+    ('顏', '顔'),
+    ('顯', '顕'),
+    ('飮', '飲'),
+    ('餘', '余'),
+    ('騷', '騒'),
+    ('驅', '駆'),
+    ('驗', '験'),
+    ('驛', '駅'),
+    ('髓', '髄'),
+    ('體', '体'),
+    ('髮', '髪'),
+    ('鬪', '闘'),
+    ('鷄', '鶏'),
+    ('麥', '麦'),
+    ('黃', '黄'),
+    ('黑', '黒'),
+    ('默', '黙'),
+    ('點', '点'),
+    ('黨', '党'),
+    ('齊', '斉'),
+    ('齋', '斎'),
+    ('齒', '歯'),
+    ('齡', '齢'),
+    ('龜', '亀'),
+];
+
+/// Full-width forms of the half-width katakana and punctuation from U+FF61.
+const HALF_WIDTH_KATAKANA: &str = "。「」、・ヲァィゥェォャュョッーアイウエオカキクケコサシスセソタチツテトナニヌネノハヒフヘホマミムメモヤユヨラリルレロワン゛゜";
+
+/// Returns the voiced form of a katakana, e.g. `ガ` for `カ`.
+fn voiced(c: char) -> Option<char> {
+    match c {
+        'ウ' => Some('ヴ'),
+        'カ' | 'キ' | 'ク' | 'ケ' | 'コ' | 'サ' | 'シ' | 'ス' | 'セ' | 'ソ' | 'タ' | 'チ'
+        | 'ツ' | 'テ' | 'ト' | 'ハ' | 'ヒ' | 'フ' | 'ヘ' | 'ホ' => {
+            char::from_u32(c as u32 + 1)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the semi-voiced form of a katakana, e.g. `パ` for `ハ`.
+fn semi_voiced(c: char) -> Option<char> {
+    match c {
+        'ハ' | 'ヒ' | 'フ' | 'ヘ' | 'ホ' => char::from_u32(c as u32 + 2),
+        _ => None,
+    }
+}
+
+/// Returns the standard form of a single character: ASCII for full-width
+/// ASCII, full-width katakana for half-width katakana and the standard form
+/// of old kanji.
+fn standard(c: char) -> char {
+    match c {
+        '！'..='～' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '｡'..='ﾟ' => HALF_WIDTH_KATAKANA
+            .chars()
+            .nth((c as u32 - 0xFF61) as usize)
+            .unwrap_or(c),
+        _ if is_kanji(c) => OLD_FORMS
+            .binary_search_by_key(&c, |&(old, _)| old)
+            .map_or(c, |i| OLD_FORMS[i].1),
+        _ => c,
+    }
+}
+
+/// A text with its orthographic variants replaced by their standard forms.
+#[derive(Debug)]
+pub(crate) struct Normalized<'a> {
+    /// The original text.
+    original: &'a str,
+    /// The normalized text.
+    pub text: Cow<'a, str>,
+    /// The byte offset of each character of the normalized text, and of the
+    /// characters it replaces in the original text, followed by the lengths
+    /// of both texts.
+    offsets: Vec<(usize, usize)>,
+}
+
+impl<'a> Normalized<'a> {
+    /// Normalizes full-width ASCII, half-width katakana and old forms of
+    /// kanji. Half-width voiced sound marks are combined with the preceding
+    /// kana, e.g. `ｶﾞ` becomes `ガ`.
+    pub fn new(original: &'a str) -> Self {
+        let mut text = String::with_capacity(original.len());
+        let mut offsets = vec![];
+
+        for (offset, c) in original.char_indices() {
+            let combined = match (c, text.chars().next_back()) {
+                ('ﾞ', Some(preceding)) => voiced(preceding),
+                ('ﾟ', Some(preceding)) => semi_voiced(preceding),
+                _ => None,
+            };
+
+            if let Some(combined) = combined {
+                text.pop();
+                text.push(combined);
+            } else {
+                offsets.push((text.len(), offset));
+                text.push(standard(c));
+            }
+        }
+        offsets.push((text.len(), original.len()));
+
+        let text = if text == original {
+            Cow::Borrowed(original)
+        } else {
+            Cow::Owned(text)
+        };
+        Self {
+            original,
+            text,
+            offsets,
+        }
+    }
+
+    /// Whether the normalized text differs from the original.
+    pub fn is_changed(&self) -> bool {
+        matches!(self.text, Cow::Owned(_))
+    }
+
+    /// Returns the byte range of the original text that a byte range of the
+    /// normalized text replaces. Both ends must be character boundaries.
+    pub fn original_range(&self, range: Range<usize>) -> Range<usize> {
+        let original = |offset: usize| {
+            self.offsets
+                .binary_search_by_key(&offset, |&(normalized, _)| normalized)
+                .map_or(offset, |i| self.offsets[i].1)
+        };
+        original(range.start)..original(range.end)
+    }
+
+    /// Returns a byte range of the normalized text, along with the original
+    /// text it replaces if the two differ.
+    pub fn slice(&self, range: Range<usize>) -> (Cow<'a, str>, Option<&'a str>) {
+        let original = &self.original[self.original_range(range.clone())];
+        let normalized = &self.text[range];
+        if original == normalized {
+            (Cow::Borrowed(original), None)
+        } else {
+            (Cow::Owned(normalized.to_string()), Some(original))
+        }
+    }
+}
+
+/// Maps reading spans over the normalized form of a text onto the text.
+/// Spans that are out of bounds are left unchanged.
+pub(crate) fn original_spans(original: &str, spans: &[ReadingSpan]) -> Vec<ReadingSpan> {
+    let normalized = Normalized::new(original);
+    // The index of the first original character of each normalized one.
+    let starts = normalized
+        .offsets
+        .iter()
+        .map(|&(_, offset)| original[..offset].chars().count())
+        .collect::<Vec<_>>();
+
+    spans
+        .iter()
+        .map(|span| {
+            let start = starts.get(span.start_index as usize);
+            let end = starts.get(span.end_index as usize + 1);
+            match (start, end) {
+                (Some(&start), Some(&end)) if start < end => ReadingSpan {
+                    start_index: u8::try_from(start).unwrap_or(span.start_index),
+                    end_index: u8::try_from(end - 1).unwrap_or(span.end_index),
+                    text: span.text.clone(),
+                },
+                _ => span.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Returns the key under which two spellings of a word are considered the
+/// same: full-width ASCII is narrowed and `々` is spelled out.
+pub(crate) fn fold(text: &str) -> Cow<'_, str> {
+    if !text.contains(|c| c == '々' || ('！'..='～').contains(&c)) {
+        return Cow::Borrowed(text);
+    }
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars() {
+        match (c, folded.chars().next_back()) {
+            ('々', Some(preceding)) if is_kanji(preceding) => folded.push(preceding),
+            _ => folded.push(standard(c)),
+        }
+    }
+    Cow::Owned(folded)
+}
+
+/// Returns the text with ASCII widened to full-width, as dictionaries such
+/// as `JMdict` write it, or `None` if it has no ASCII.
+pub(crate) fn widen(text: &str) -> Option<String> {
+    text.contains(|c| ('!'..='~').contains(&c)).then(|| {
+        text.chars()
+            .map(|c| match c {
+                '!'..='~' => char::from_u32(c as u32 + 0xFEE0).unwrap_or(c),
+                _ => c,
+            })
+            .collect()
+    })
+}
+
+/// Returns other spellings of a word to look up if it is missing from the
+/// dictionary: with repeated kanji written with `々` or `々` spelled out,
+/// and with ASCII widened to full-width.
+pub(crate) fn variants(text: &str) -> Vec<String> {
+    let mut marked = String::with_capacity(text.len());
+    let mut spelled = String::with_capacity(text.len());
+    for c in text.chars() {
+        let preceding = spelled
+            .chars()
+            .next_back()
+            .filter(|&p| is_kanji(p) && p != '々');
+        match (c, preceding) {
+            ('々', Some(preceding)) => {
+                marked.push(c);
+                spelled.push(preceding);
+            }
+            (c, Some(preceding)) if c == preceding => {
+                marked.push('々');
+                spelled.push(c);
+            }
+            _ => {
+                marked.push(c);
+                spelled.push(c);
+            }
+        }
+    }
+
+    let mut variants = vec![];
+    for variant in [text.to_string(), marked, spelled] {
+        let widened = widen(&variant);
+        for variant in [Some(variant), widened].into_iter().flatten() {
+            if variant != text && !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        }
+    }
+    variants
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::{dictionary, normalize};
+
+    #[test]
+    fn normalize_variants() {
+        use dictionary::ReadingSpan;
+        use normalize::Normalized;
+
+        let normalized = Normalized::new("ｶﾞｲﾄﾞ舊ＡＢ");
+        assert_eq!(normalized.text, "ガイド旧AB");
+        // ド in the normalized text replaces ﾄﾞ
+        assert_eq!(normalized.original_range(6..9), 9..15);
+        assert!(!Normalized::new("ガイド").is_changed());
+        assert_eq!(Normalized::new("亂國龜").text, "乱国亀");
+
+        let spans = normalize::original_spans(
+            "ﾊﾞｽ停",
+            &[ReadingSpan {
+                start_index: 2,
+                end_index: 2,
+                text: "てい".to_string(),
+            }],
+        );
+        assert_eq!(
+            spans
+                .iter()
+                .map(|span| (span.start_index, span.end_index))
+                .collect::<Vec<_>>(),
+            [(3, 3)]
+        );
+
+        assert_eq!(normalize::fold("時々ＡＢ"), "時時AB");
+        assert!(normalize::variants("時時").contains(&"時々".to_string()));
+        assert!(normalize::variants("人々").contains(&"人人".to_string()));
+        assert_eq!(normalize::widen("Tシャツ").as_deref(), Some("Ｔシャツ"));
+        assert_eq!(normalize::widen("シャツ"), None);
+    }
+}
@@ -1,14 +1,17 @@
 use std::num::ParseIntError;
 
 use nom::{
-    bytes::complete::{take_till1, take_until},
-    character::complete::{char, digit1},
-    combinator::{map, map_res, opt},
-    multi::separated_list0,
-    sequence::{preceded, separated_pair, tuple},
+    branch::alt,
+    bytes::complete::{escaped, is_not, tag, tag_no_case, take_till1, take_until},
+    character::complete::{anychar, char, digit1, satisfy},
+    combinator::{cond, map, map_res, opt, peek, recognize},
+    multi::{many0, separated_list0},
+    sequence::{delimited, pair, preceded, separated_pair, tuple},
     IResult,
 };
 
+use crate::chars::is_kanji;
+
 #[derive(Debug)]
 pub struct TextEntry<'a> {
     pub text: &'a str,
@@ -38,7 +41,7 @@ pub fn take_range(input: &str) -> IResult<&str, (u8, u8)> {
     )(input)
 }
 
-pub fn take_reading_span(input: &str) -> IResult<&str, ReadingSpan> {
+pub fn take_reading_span(input: &str) -> IResult<&str, ReadingSpan<'_>> {
     map(
         separated_pair(take_range, char(':'), take_till1(|c| c == '\n' || c == ';')),
         |((start_index, end_index), text)| ReadingSpan {
@@ -49,11 +52,11 @@ pub fn take_reading_span(input: &str) -> IResult<&str, ReadingSpan> {
     )(input)
 }
 
-pub fn take_reading_spans(input: &str) -> IResult<&str, Vec<ReadingSpan>> {
+pub fn take_reading_spans(input: &str) -> IResult<&str, Vec<ReadingSpan<'_>>> {
     separated_list0(char(';'), take_reading_span)(input)
 }
 
-pub fn dictionary_line(input: &str) -> IResult<&str, TextEntry> {
+pub fn dictionary_line(input: &str) -> IResult<&str, TextEntry<'_>> {
     map(
         tuple((
             take_until("|"),
@@ -69,3 +72,552 @@ pub fn dictionary_line(input: &str) -> IResult<&str, TextEntry> {
         },
     )(input)
 }
+
+/// An entry of the `JMnedict` proper name dictionary.
+#[derive(Debug)]
+pub struct NameEntry<'a> {
+    /// Pairs of kanji writing and reading.
+    pub pairs: Vec<(String, String)>,
+    /// Name type entities, e.g. `place` for `&place;`.
+    pub name_types: Vec<&'a str>,
+}
+
+/// Returns the contents of every element with the given name, e.g.
+/// `<keb>..</keb>`. Elements must not have attributes.
+fn xml_elements<'a>(input: &'a str, name: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+    let mut rest = input;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let len = rest[start..].find(&close)?;
+        let content = &rest[start..start + len];
+        rest = &rest[start + len + close.len()..];
+        Some(content)
+    })
+}
+
+/// Parses the entries of a `JMnedict` XML file. Entries without a kanji
+/// writing have no pairs.
+pub fn jmnedict_entries(input: &str) -> impl Iterator<Item = NameEntry<'_>> {
+    xml_elements(input, "entry").map(|entry| {
+        let kanji = xml_elements(entry, "keb").collect::<Vec<_>>();
+
+        let mut pairs = vec![];
+        for reading_element in xml_elements(entry, "r_ele") {
+            let Some(reading) = xml_elements(reading_element, "reb").next() else {
+                continue;
+            };
+            let restrictions = xml_elements(reading_element, "re_restr").collect::<Vec<_>>();
+            for text in &kanji {
+                if restrictions.is_empty() || restrictions.contains(text) {
+                    pairs.push((unescape_html(text), unescape_html(reading)));
+                }
+            }
+        }
+
+        let name_types = xml_elements(entry, "name_type")
+            .map(|t| t.trim().trim_start_matches('&').trim_end_matches(';'))
+            .collect();
+
+        NameEntry { pairs, name_types }
+    })
+}
+
+/// A pair of kanji writing and reading of a `JMdict` entry, with whether
+/// each is common.
+#[derive(Debug)]
+pub struct FrequencyPair {
+    /// The kanji writing.
+    pub text: String,
+    /// Whether the kanji writing is common.
+    pub text_common: bool,
+    /// The reading.
+    pub reading: String,
+    /// Whether the reading is common.
+    pub reading_common: bool,
+}
+
+/// Whether the priorities of a `JMdict` element (`ke_pri` or `re_pri`) mark
+/// it as common.
+fn is_common<'a>(mut priorities: impl Iterator<Item = &'a str>) -> bool {
+    priorities.any(|p| matches!(p.trim(), "news1" | "ichi1" | "spec1" | "spec2" | "gai1"))
+}
+
+/// Parses the pairs of kanji writing and reading of a `JMdict` XML file,
+/// with their frequency.
+pub fn jmdict_pairs(input: &str) -> impl Iterator<Item = FrequencyPair> + '_ {
+    xml_elements(input, "entry").flat_map(|entry| {
+        let kanji = xml_elements(entry, "k_ele")
+            .filter_map(|k| {
+                let text = xml_elements(k, "keb").next()?;
+                Some((text, is_common(xml_elements(k, "ke_pri"))))
+            })
+            .collect::<Vec<_>>();
+
+        let mut pairs = vec![];
+        for reading_element in xml_elements(entry, "r_ele") {
+            let Some(reading) = xml_elements(reading_element, "reb").next() else {
+                continue;
+            };
+            // Readings marked `re_nokanji` do not apply to the kanji writings.
+            if reading_element.contains("<re_nokanji") {
+                continue;
+            }
+            let reading_common = is_common(xml_elements(reading_element, "re_pri"));
+            let restrictions = xml_elements(reading_element, "re_restr").collect::<Vec<_>>();
+            for &(text, text_common) in &kanji {
+                if restrictions.is_empty() || restrictions.contains(&text) {
+                    pairs.push(FrequencyPair {
+                        text: unescape_html(text),
+                        text_common,
+                        reading: unescape_html(reading),
+                        reading_common,
+                    });
+                }
+            }
+        }
+        pairs
+    })
+}
+
+/// A piece of text that may contain ruby annotations.
+#[derive(Debug)]
+pub enum Chunk<'a> {
+    /// Text without annotations.
+    Plain(&'a str),
+    /// An annotation.
+    Ruby {
+        /// The annotation, including its markup.
+        source: &'a str,
+        /// Pairs of base text and reading text.
+        pairs: Vec<(String, String)>,
+    },
+}
+
+/// Splits the input into plain text and the annotations recognized by the
+/// `ruby` parser.
+pub fn chunks<'a>(
+    input: &'a str,
+    mut ruby: impl FnMut(&'a str) -> IResult<&'a str, Vec<(String, String)>>,
+) -> Vec<Chunk<'a>> {
+    let mut chunks = vec![];
+    let mut plain_start = 0;
+    let mut position = 0;
+
+    while position < input.len() {
+        match ruby(&input[position..]) {
+            Ok((rest, pairs)) if !pairs.is_empty() => {
+                if plain_start < position {
+                    chunks.push(Chunk::Plain(&input[plain_start..position]));
+                }
+                let end = input.len() - rest.len();
+                chunks.push(Chunk::Ruby {
+                    source: &input[position..end],
+                    pairs,
+                });
+                position = end;
+                plain_start = end;
+            }
+            _ => {
+                position += input[position..].chars().next().map_or(1, char::len_utf8);
+            }
+        }
+    }
+
+    if plain_start < input.len() {
+        chunks.push(Chunk::Plain(&input[plain_start..]));
+    }
+
+    chunks
+}
+
+/// Splits a string on a separator that is not preceded by an escape
+/// character.
+fn split_unescaped(input: &str, separator: char, escape: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == escape {
+            chars.next();
+        } else if c == separator {
+            parts.push(&input[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Pairs up base text and reading text. If both are split into the same
+/// number of parts (or the base is not split, but has as many characters as
+/// the reading has parts), each part is annotated separately (jukugo ruby).
+/// Otherwise, the whole base is annotated with the whole reading.
+fn pair_up(bases: Vec<String>, readings: Vec<String>) -> Vec<(String, String)> {
+    let bases = if bases.len() == 1 && readings.len() > 1 {
+        bases[0].chars().map(String::from).collect()
+    } else {
+        bases
+    };
+
+    if bases.len() == readings.len() {
+        bases.into_iter().zip(readings).collect()
+    } else {
+        vec![(bases.concat(), readings.concat())]
+    }
+}
+
+fn unescape_markdown(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&next) if c == '\\' && next.is_ascii_punctuation() => {
+                output.push(next);
+                chars.next();
+            }
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+/// Parses a Markdown annotation, e.g. `[漢字]{かんじ}` or `[漢字]{かん|じ}`.
+pub fn markdown_ruby(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    map(
+        tuple((
+            delimited(
+                char('['),
+                escaped(is_not("\\]\n"), '\\', anychar),
+                char(']'),
+            ),
+            delimited(
+                char('{'),
+                escaped(is_not("\\}\n"), '\\', anychar),
+                char('}'),
+            ),
+        )),
+        |(base, reading): (&str, &str)| {
+            if base.is_empty() || reading.is_empty() {
+                return vec![];
+            }
+            pair_up(
+                vec![unescape_markdown(base)],
+                split_unescaped(reading, '|', '\\')
+                    .into_iter()
+                    .map(unescape_markdown)
+                    .collect(),
+            )
+        },
+    )(input)
+}
+
+fn unescape_latex(input: &str) -> String {
+    const ESCAPES: [(&str, &str); 15] = [
+        ("\\textbackslash{}", "\\"),
+        ("\\textbar{}", "|"),
+        ("\\textasciicircum{}", "^"),
+        ("\\textasciitilde{}", "~"),
+        ("\\^{}", "^"),
+        ("\\~{}", "~"),
+        ("\\{", "{"),
+        ("\\}", "}"),
+        ("\\%", "%"),
+        ("\\$", "$"),
+        ("\\#", "#"),
+        ("\\&", "&"),
+        ("\\_", "_"),
+        ("\\|", "|"),
+        ("\\ ", " "),
+    ];
+
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    'outer: while let Some(c) = rest.chars().next() {
+        if c == '\\' {
+            for (escape, replacement) in ESCAPES {
+                if let Some(r) = rest.strip_prefix(escape) {
+                    output.push_str(replacement);
+                    rest = r;
+                    continue 'outer;
+                }
+            }
+        }
+        output.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    output
+}
+
+/// Parses a LaTeX group, e.g. `{漢字}`, returning its content.
+fn latex_group(input: &str) -> IResult<&str, &str> {
+    delimited(
+        char('{'),
+        recognize(many0(alt((
+            recognize(pair(char('\\'), anychar)),
+            recognize(latex_group),
+            is_not("\\{}"),
+        )))),
+        char('}'),
+    )(input)
+}
+
+/// Parses a LaTeX annotation, e.g. `\ruby{漢字}{かんじ}` or
+/// `\ruby[j]{漢|字}{かん|じ}`.
+pub fn latex_ruby(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    map(
+        preceded(
+            pair(
+                tag("\\ruby"),
+                opt(delimited(char('['), take_until("]"), char(']'))),
+            ),
+            pair(latex_group, latex_group),
+        ),
+        |(base, reading)| {
+            if base.is_empty() || reading.is_empty() {
+                return vec![];
+            }
+            let split = |text| {
+                split_unescaped(text, '|', '\\')
+                    .into_iter()
+                    .map(unescape_latex)
+                    .collect()
+            };
+            pair_up(split(base), split(reading))
+        },
+    )(input)
+}
+
+/// Replaces HTML character references.
+pub fn unescape_html(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(c) = rest.chars().next() {
+        if c == '&' {
+            if let Some(end) = rest.find(';').filter(|&end| end <= 10) {
+                let name = &rest[1..end];
+                let replacement = match name {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some('\u{a0}'),
+                    _ => name
+                        .strip_prefix("#x")
+                        .or_else(|| name.strip_prefix("#X"))
+                        .map(|hex| u32::from_str_radix(hex, 16))
+                        .or_else(|| name.strip_prefix('#').map(str::parse))
+                        .and_then(Result::ok)
+                        .and_then(char::from_u32),
+                };
+                if let Some(replacement) = replacement {
+                    output.push(replacement);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        }
+        output.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    output
+}
+
+/// Parses an HTML tag with the given name, e.g. `<rt>` or `</rt>`.
+fn html_tag<'a>(name: &'static str, end: bool) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    recognize(tuple((
+        char('<'),
+        cond(end, char('/')),
+        tag_no_case(name),
+        peek(satisfy(|c| c == '>' || c.is_whitespace())),
+        take_until(">"),
+        char('>'),
+    )))
+}
+
+#[derive(Debug)]
+enum RubyContent<'a> {
+    Base(&'a str),
+    Text(&'a str),
+    Ignored,
+}
+
+/// Parses an HTML ruby element, e.g.
+/// `<ruby>漢<rp>(</rp><rt>かん</rt><rp>)</rp></ruby>` or
+/// `<ruby><rb>漢</rb><rb>字</rb><rt>かん</rt><rt>じ</rt></ruby>`.
+pub fn html_ruby(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    let element = |name: &'static str| {
+        delimited(
+            html_tag(name, false),
+            map(opt(is_not("<")), Option::unwrap_or_default),
+            opt(html_tag(name, true)),
+        )
+    };
+
+    let content = alt((
+        map(element("rb"), RubyContent::Base),
+        map(element("rt"), RubyContent::Text),
+        map(element("rp"), |_| RubyContent::Ignored),
+        map(alt((html_tag("rtc", false), html_tag("rtc", true))), |_| {
+            RubyContent::Ignored
+        }),
+        map(is_not("<"), RubyContent::Base),
+    ));
+
+    map(
+        delimited(
+            html_tag("ruby", false),
+            many0(content),
+            html_tag("ruby", true),
+        ),
+        |contents| {
+            let mut pairs = vec![];
+            let mut bases: Vec<&str> = vec![];
+            let mut readings: Vec<&str> = vec![];
+            for content in contents.into_iter().chain([RubyContent::Base("")]) {
+                match content {
+                    RubyContent::Base(base) => {
+                        if !readings.is_empty() {
+                            pairs.extend(pair_up(
+                                bases.drain(..).map(unescape_html).collect(),
+                                readings.drain(..).map(unescape_html).collect(),
+                            ));
+                        }
+                        if !base.is_empty() {
+                            bases.push(base);
+                        }
+                    }
+                    RubyContent::Text(reading) => readings.push(reading),
+                    RubyContent::Ignored => {}
+                }
+            }
+            if bases.iter().any(|b| !b.trim().is_empty()) {
+                // Trailing base text without a reading cannot be represented.
+                return vec![];
+            }
+            pairs
+        },
+    )(input)
+}
+
+fn unescape_aozora(input: &str) -> String {
+    input
+        .replace("※［＃縦線、1-1-35］", "｜")
+        .replace("※［＃始め二重山括弧、1-1-52］", "《")
+        .replace("※［＃終わり二重山括弧、1-1-53］", "》")
+}
+
+/// Splits Aozora Bunko text into plain text and annotations, e.g.
+/// `｜お茶《おちゃ》` or `漢字《かんじ》`.
+///
+/// Without a `｜` delimiter, the base is the run of kanji immediately
+/// preceding the reading.
+pub fn aozora_chunks(input: &str) -> Vec<Chunk<'_>> {
+    let mut chunks = vec![];
+    let mut plain_start = 0;
+    let mut delimiter = None;
+    let mut position = 0;
+
+    while let Some(c) = input[position..].chars().next() {
+        match c {
+            '｜' => delimiter = Some(position),
+            // A delimiter without a reading on the same line is plain text.
+            '\n' => delimiter = None,
+            '《' => {
+                let Some(reading_len) = input[position..].find('》') else {
+                    break;
+                };
+                let reading = &input[position + c.len_utf8()..position + reading_len];
+                let end = position + reading_len + '》'.len_utf8();
+
+                let (start, base) = if let Some(start) = delimiter {
+                    (start, &input[start + '｜'.len_utf8()..position])
+                } else {
+                    let base_start = input[plain_start..position]
+                        .char_indices()
+                        .rev()
+                        .take_while(|(_, c)| is_kanji(*c))
+                        .last()
+                        .map_or(position, |(i, _)| plain_start + i);
+                    (base_start, &input[base_start..position])
+                };
+
+                if !base.is_empty() && !reading.is_empty() {
+                    if plain_start < start {
+                        chunks.push(Chunk::Plain(&input[plain_start..start]));
+                    }
+                    chunks.push(Chunk::Ruby {
+                        source: &input[start..end],
+                        pairs: vec![(unescape_aozora(base), unescape_aozora(reading))],
+                    });
+                    plain_start = end;
+                }
+
+                delimiter = None;
+                position = end;
+                continue;
+            }
+            _ => {}
+        }
+        position += c.len_utf8();
+    }
+
+    if plain_start < input.len() {
+        chunks.push(Chunk::Plain(&input[plain_start..]));
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::{format, select};
+
+    #[test]
+    fn parse_formats() {
+        use format::Parse;
+
+        let tests: [(&dyn Parse, &str); 4] = [
+            (&format::Markdown, r"お[元]{げん}[気]{き}と[\[漢\]]{かん}と[計算]{けい|さん}"),
+            (
+                &format::Html,
+                "お<ruby>元<rp>(</rp><rt>げん</rt><rp>)</rp></ruby><ruby>気<rt>き</rt></ruby>と<ruby>[漢]<rt>かん</rt></ruby>と<ruby><rb>計</rb><rb>算</rb><rp>(</rp><rt>けい</rt><rt>さん</rt><rp>)</rp></ruby>",
+            ),
+            (
+                &format::Latex,
+                r"お\ruby{元}{げん}\ruby{気}{き}と\ruby{[漢]}{かん}と\ruby[j]{計|算}{けい|さん}",
+            ),
+            (
+                &format::Aozora,
+                "お元《げん》気《き》と｜[漢]《かん》と｜計《けい》｜算《さん》",
+            ),
+        ];
+
+        for (format, input) in tests {
+            let parsed = format.parse(input);
+            assert_eq!(
+                parsed.render(&select::heuristic::All, &format::Markdown),
+                r"お[元]{げん}[気]{き}と[\[漢\]]{かん}と[計]{けい}[算]{さん}",
+            );
+            assert_eq!(
+                parsed
+                    .fragments
+                    .iter()
+                    .map(|f| f.text.as_ref())
+                    .collect::<Vec<_>>(),
+                ["お", "元気", "と", "[漢]", "と", "計算"],
+            );
+        }
+
+        // Reading spans cannot index past 256 characters.
+        let long = "[字]{じ}".repeat(257);
+        let parsed = format::Markdown.parse(&long);
+        assert_eq!(parsed.fragments.len(), 1);
+        assert_eq!(parsed.fragments[0].text.chars().count(), 257);
+        assert!(parsed.fragments[0].annotations.is_empty());
+    }
+}
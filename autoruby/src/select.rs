@@ -19,8 +19,8 @@ pub mod heuristic {
     pub struct All;
 
     impl<'a> Select<'a> for All {
-        fn select(&self, fragment: &AnnotatedTextFragment<'a>) -> Option<&'a TextEntry> {
-            fragment.annotations.get(0).copied()
+        fn select(&self, fragment: &'a AnnotatedTextFragment<'a>) -> Option<&'a TextEntry> {
+            fragment.annotations.first().map(AsRef::as_ref)
         }
     }
 
@@ -28,8 +28,8 @@ pub mod heuristic {
     pub struct UncommonOnly;
 
     impl<'a> Select<'a> for UncommonOnly {
-        fn select(&self, fragment: &AnnotatedTextFragment<'a>) -> Option<&'a TextEntry> {
-            match fragment.annotations.get(0) {
+        fn select(&self, fragment: &'a AnnotatedTextFragment<'a>) -> Option<&'a TextEntry> {
+            match fragment.annotations.first() {
                 Some(entry) if !entry.text_is_common && !entry.reading_is_common => Some(entry),
                 _ => None,
             }
@@ -42,10 +42,13 @@ pub mod filter {
 
     use std::{
         collections::HashSet,
-        sync::{Arc, RwLock},
+        sync::{Arc, PoisonError, RwLock},
     };
 
-    use crate::{annotate::AnnotatedTextFragment, dictionary::TextEntry};
+    use crate::{
+        annotate::AnnotatedTextFragment,
+        dictionary::{NameType, TextEntry},
+    };
 
     use super::Select;
 
@@ -54,6 +57,7 @@ pub mod filter {
     pub struct FirstOccurrence<'a, S: Select<'a>> {
         seen: Arc<RwLock<HashSet<&'a str>>>,
         selector: S,
+        count_locked: bool,
     }
 
     impl<'a, S: Select<'a>> FirstOccurrence<'a, S> {
@@ -63,13 +67,26 @@ pub mod filter {
             Self {
                 seen: Arc::default(),
                 selector,
+                count_locked: false,
             }
         }
+
+        /// Treats locked fragments (e.g. annotations written by the author)
+        /// as occurrences, so that later occurrences of the same text are not
+        /// annotated.
+        #[must_use]
+        pub fn counting_locked(mut self) -> Self {
+            self.count_locked = true;
+            self
+        }
     }
 
     impl<'a, S: Select<'a>> Select<'a> for FirstOccurrence<'a, S> {
         fn select(&'_ self, fragment: &'a AnnotatedTextFragment<'a>) -> Option<&'a TextEntry> {
-            let mut set = self.seen.write().unwrap();
+            if fragment.is_locked() && !self.count_locked {
+                return None;
+            }
+            let mut set = self.seen.write().unwrap_or_else(PoisonError::into_inner);
             if (*set).insert(&fragment.text) {
                 self.selector.select(fragment)
             } else {
@@ -77,4 +94,63 @@ pub mod filter {
             }
         }
     }
+
+    /// Filters out annotations that are not proper nouns.
+    #[derive(Clone, Debug)]
+    pub struct ProperNouns<S> {
+        selector: S,
+        name_types: Vec<NameType>,
+    }
+
+    impl<S> ProperNouns<S> {
+        /// Creates a new annotation selector that only selects proper nouns
+        /// of any type.
+        pub fn new(selector: S) -> Self {
+            Self {
+                selector,
+                name_types: vec![],
+            }
+        }
+
+        /// Only selects proper nouns of the given type. May be called more
+        /// than once to select several types.
+        #[must_use]
+        pub fn of_type(mut self, name_type: NameType) -> Self {
+            self.name_types.push(name_type);
+            self
+        }
+    }
+
+    impl<'a, S: Select<'a>> Select<'a> for ProperNouns<S> {
+        fn select(&'_ self, fragment: &'a AnnotatedTextFragment<'a>) -> Option<&'a TextEntry> {
+            self.selector.select(fragment).filter(|entry| {
+                entry.name_types.iter().any(|name_type| {
+                    self.name_types.is_empty() || self.name_types.contains(name_type)
+                })
+            })
+        }
+    }
+
+    /// Filters out annotations whose readings were guessed, e.g. for words
+    /// missing from the dictionary.
+    #[derive(Clone, Debug)]
+    pub struct Confident<S> {
+        selector: S,
+    }
+
+    impl<S> Confident<S> {
+        /// Creates a new annotation selector that only selects annotations
+        /// that are not low confidence.
+        pub fn new(selector: S) -> Self {
+            Self { selector }
+        }
+    }
+
+    impl<'a, S: Select<'a>> Select<'a> for Confident<S> {
+        fn select(&'_ self, fragment: &'a AnnotatedTextFragment<'a>) -> Option<&'a TextEntry> {
+            self.selector
+                .select(fragment)
+                .filter(|entry| !entry.low_confidence)
+        }
+    }
 }